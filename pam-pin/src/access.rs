@@ -0,0 +1,235 @@
+use crate::{Error, Result};
+use error_stack::{Report, ResultExt};
+use pamsm::PamError;
+use pin_data::User;
+
+/// Where a pin may be used.
+/// `None` allows everything.
+#[derive(Default, Clone, Debug)]
+pub(crate) struct Restrictions {
+    pub allowed_services: Option<Vec<String>>,
+    /// Reject sessions with a remote host.
+    pub deny_remote: bool,
+    /// Compared without a leading `/dev/`.
+    pub allowed_ttys: Option<Vec<String>>,
+}
+
+/// The PAM items the restrictions are checked against.
+#[derive(Default, Clone, Copy, Debug)]
+pub(crate) struct Origin<'a> {
+    pub service: Option<&'a str>,
+    pub tty: Option<&'a str>,
+    pub rhost: Option<&'a str>,
+}
+
+impl Restrictions {
+    /// The fields of the user take precedence over the module args.
+    pub fn for_user(&self, user: Option<&User>) -> Self {
+        let Some(user) = user else {
+            return self.clone();
+        };
+        Self {
+            allowed_services: user
+                .allowed_services()
+                .map(<[String]>::to_vec)
+                .or_else(|| self.allowed_services.clone()),
+            deny_remote: user.deny_remote().unwrap_or(self.deny_remote),
+            allowed_ttys: user
+                .allowed_ttys()
+                .map(<[String]>::to_vec)
+                .or_else(|| self.allowed_ttys.clone()),
+        }
+    }
+
+    pub fn check(&self, origin: Origin<'_>) -> Result<()> {
+        let denied = |item: String| {
+            Err(Report::new(Error::AccessDenied))
+                .attach_printable(item)
+                .attach(PamError::PERM_DENIED)
+        };
+
+        if let Some(allowed_services) = &self.allowed_services {
+            if !origin
+                .service
+                .is_some_and(|service| allowed_services.iter().any(|allowed| allowed == service))
+            {
+                return denied(format!("service: {:?}", origin.service));
+            }
+        }
+        if self.deny_remote && origin.rhost.is_some_and(|rhost| !rhost.is_empty()) {
+            return denied(format!("remote host: {:?}", origin.rhost));
+        }
+        if let Some(allowed_ttys) = &self.allowed_ttys {
+            let is_allowed = origin.tty.is_some_and(|tty| {
+                allowed_ttys
+                    .iter()
+                    .any(|allowed| strip_dev(allowed) == strip_dev(tty))
+            });
+            if !is_allowed {
+                return denied(format!("tty: {:?}", origin.tty));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which users may use a pin at all, by their Unix groups.
+/// Excluded users are ignored by the module.
+#[derive(Default, Clone, Debug)]
+pub(crate) struct GroupPolicy {
+    /// A member of any of them is required.
+    pub groups: Option<Vec<String>>,
+    /// Members of any of them are excluded.
+    pub deny_groups: Vec<String>,
+}
+
+impl GroupPolicy {
+    /// Looks the groups up through NSS, so it has to be called before the sandbox is active.
+    pub fn check(&self, user_name: &str) -> Result<()> {
+        if self.groups.is_none() && self.deny_groups.is_empty() {
+            return Ok(());
+        }
+
+        let group_ids = match pam_utils::get_passwd(user_name).change_context(Error::Groups)? {
+            Some(passwd) => {
+                pam_utils::get_group_list(user_name, passwd.gid).change_context(Error::Groups)?
+            }
+            None => Vec::new(),
+        };
+        let mut group_id_error = None;
+        let is_member = |group: &str| match pam_utils::get_group_id(group) {
+            Ok(group_id) => group_id.is_some_and(|group_id| group_ids.contains(&group_id)),
+            Err(error) => {
+                group_id_error.get_or_insert(error);
+                false
+            }
+        };
+
+        let res = self.check_membership(is_member);
+        if let Some(error) = group_id_error {
+            return Err(Report::new(error).change_context(Error::Groups));
+        }
+        res
+    }
+
+    fn check_membership(&self, mut is_member: impl FnMut(&str) -> bool) -> Result<()> {
+        let excluded = |group: &str| {
+            Err(Report::new(Error::ExcludedByGroup))
+                .attach_printable(format!("group: {group}"))
+                .attach(PamError::IGNORE)
+        };
+
+        if let Some(groups) = &self.groups {
+            if !groups.iter().any(|group| is_member(group)) {
+                return excluded(&groups.join(","));
+            }
+        }
+        if let Some(group) = self.deny_groups.iter().find(|group| is_member(group)) {
+            return excluded(group);
+        }
+        Ok(())
+    }
+}
+
+fn strip_dev(tty: &str) -> &str {
+    tty.strip_prefix("/dev/").unwrap_or(tty)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "$argon2d$v=19$m=4096,t=3,p=1$PFRID+hbQKjEFESZWQZMEA$mMpICfZn5N0bV13RJ3nWYfYXesgTJcPl81xwrqzDDLY";
+
+    fn local_only() -> Restrictions {
+        Restrictions {
+            allowed_services: Some(vec!["sudo".into(), "swaylock".into()]),
+            deny_remote: true,
+            allowed_ttys: None,
+        }
+    }
+
+    #[test]
+    fn check_origin() {
+        let restrictions = local_only();
+        let sudo = Origin {
+            service: Some("sudo"),
+            tty: Some("/dev/pts/0"),
+            rhost: None,
+        };
+
+        restrictions.check(sudo).unwrap();
+        let _ = restrictions
+            .check(Origin {
+                service: Some("sshd"),
+                ..sudo
+            })
+            .unwrap_err();
+        let _ = restrictions
+            .check(Origin {
+                rhost: Some("example.org"),
+                ..sudo
+            })
+            .unwrap_err();
+        let _ = restrictions.check(Origin::default()).unwrap_err();
+
+        let restrictions = Restrictions {
+            allowed_ttys: Some(vec!["pts/0".into()]),
+            ..local_only()
+        };
+        restrictions.check(sudo).unwrap();
+        let _ = restrictions
+            .check(Origin {
+                tty: Some("tty1"),
+                ..sudo
+            })
+            .unwrap_err();
+    }
+
+    #[test]
+    fn check_group_membership() {
+        let is_member = |group: &str| ["users", "pin-users"].contains(&group);
+
+        GroupPolicy::default().check_membership(is_member).unwrap();
+        GroupPolicy {
+            groups: Some(vec!["wheel".into(), "pin-users".into()]),
+            deny_groups: vec!["no-pin".into()],
+        }
+        .check_membership(is_member)
+        .unwrap();
+
+        let error = GroupPolicy {
+            groups: Some(vec!["wheel".into()]),
+            deny_groups: Vec::new(),
+        }
+        .check_membership(is_member)
+        .unwrap_err();
+        assert_eq!(error.downcast_ref::<PamError>(), Some(&PamError::IGNORE));
+        let _ = GroupPolicy {
+            groups: None,
+            deny_groups: vec!["users".into()],
+        }
+        .check_membership(is_member)
+        .unwrap_err();
+    }
+
+    #[test]
+    fn user_overrides_defaults() {
+        let mut user = User::new(
+            "user",
+            argon2::password_hash::PasswordHashString::new(HASH).unwrap(),
+        );
+        user.set_allowed_services(Some(vec!["sshd".into()]));
+        user.set_deny_remote(Some(false));
+
+        let restrictions = local_only().for_user(Some(&user));
+        restrictions
+            .check(Origin {
+                service: Some("sshd"),
+                tty: None,
+                rhost: Some("example.org"),
+            })
+            .unwrap();
+        assert!(local_only().for_user(None).deny_remote);
+    }
+}
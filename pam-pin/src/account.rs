@@ -0,0 +1,90 @@
+use pin_data::User;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Expiry {
+    Valid,
+    /// The pin stays valid for the given number of (started) days.
+    ExpiresSoon(u64),
+    /// The pin is older than the maximum age and has to be changed.
+    ChangeRequired,
+    /// The pin has reached its fixed expiry date.
+    Expired,
+}
+
+/// The fixed expiry date, which also applies to the `auth` stack.
+pub(crate) fn is_expired(user: &User, now: u64) -> bool {
+    user.expires_at()
+        .is_some_and(|expires_at| now >= expires_at)
+}
+
+/// `max_age` and `warn_age` are in days.
+pub(crate) fn expiry(user: &User, max_age: Option<u64>, warn_age: u64, now: u64) -> Expiry {
+    if is_expired(user, now) {
+        return Expiry::Expired;
+    }
+
+    let change_at = max_age.zip(user.created_at()).map(|(max_age, created_at)| {
+        created_at.saturating_add(max_age.saturating_mul(SECONDS_PER_DAY))
+    });
+    if change_at.is_some_and(|change_at| now >= change_at) {
+        return Expiry::ChangeRequired;
+    }
+
+    let invalid_at = [user.expires_at(), change_at].into_iter().flatten().min();
+    match invalid_at {
+        Some(invalid_at) if invalid_at - now <= warn_age.saturating_mul(SECONDS_PER_DAY) => {
+            Expiry::ExpiresSoon((invalid_at - now).div_ceil(SECONDS_PER_DAY))
+        }
+        _ => Expiry::Valid,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "$argon2d$v=19$m=4096,t=3,p=1$PFRID+hbQKjEFESZWQZMEA$mMpICfZn5N0bV13RJ3nWYfYXesgTJcPl81xwrqzDDLY";
+
+    fn user(created_at: u64, expires_at: Option<u64>) -> User {
+        let mut user = User::new(
+            "user",
+            argon2::password_hash::PasswordHashString::new(HASH).unwrap(),
+        );
+        user.set_created_at(Some(created_at));
+        user.set_expires_at(expires_at);
+        user
+    }
+
+    #[test]
+    fn fixed_expiry() {
+        let user = user(0, Some(10 * SECONDS_PER_DAY));
+
+        assert_eq!(expiry(&user, None, 7, 0), Expiry::Valid);
+        assert_eq!(
+            expiry(&user, None, 7, 5 * SECONDS_PER_DAY),
+            Expiry::ExpiresSoon(5)
+        );
+        assert_eq!(
+            expiry(&user, None, 7, 10 * SECONDS_PER_DAY),
+            Expiry::Expired
+        );
+    }
+
+    #[test]
+    fn maximum_age() {
+        let user = user(0, None);
+
+        assert_eq!(expiry(&user, Some(30), 7, 0), Expiry::Valid);
+        assert_eq!(
+            expiry(&user, Some(30), 7, 29 * SECONDS_PER_DAY + 1),
+            Expiry::ExpiresSoon(1)
+        );
+        assert_eq!(
+            expiry(&user, Some(30), 7, 30 * SECONDS_PER_DAY),
+            Expiry::ChangeRequired
+        );
+        assert_eq!(expiry(&user, None, 7, u64::MAX), Expiry::Valid);
+    }
+}
@@ -1,21 +1,457 @@
+use crate::access::{GroupPolicy, Restrictions};
+use crate::cost::{CostLimit, CostLimits};
+use crate::rehash::RehashTarget;
+use pamsm::PamError;
+use std::num::NonZeroU32;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 pub(crate) struct Args {
-    pub database_filepath: PathBuf,
+    pub database: Database,
+    pub database_match: DatabaseMatch,
+    /// A writeable directory for the [`pin_data::state::UserState`]s.
+    pub state_dir: Option<PathBuf>,
+    pub lockout: Option<LockoutArgs>,
+    /// In hours. Requires the state directory.
+    pub max_password_age: Option<u64>,
+    /// Record a password login instead of authenticating.
+    pub is_mark: bool,
+    /// In days.
+    pub max_age: Option<u64>,
+    /// In days.
+    pub warn_age: u64,
+    pub first_pass: FirstPass,
+    /// Prompts per authentication.
+    pub retry: NonZeroU32,
+    /// In milliseconds, after each failed attempt.
+    pub fail_delay: u64,
+    /// Take the new pin of `chauthtok` from a previous module.
+    pub use_authtok: bool,
+    /// How many previous pins can't be reused by `chauthtok`.
+    pub remember: usize,
+    /// Checked before verifying and for new pins.
+    pub pin_policy: pin_data::PinPolicy,
+    /// Prompt and verify for users without a pin as well.
+    pub conceal_unknown_user: bool,
+    pub cost_limits: CostLimits,
+    /// A root-only file with the secret keys for Argon2.
+    pub pepper: Option<PathBuf>,
+    /// Defaults for the fields of the database entries.
+    pub restrictions: Restrictions,
+    pub group_policy: GroupPolicy,
+    /// Disables all pins while it exists.
+    pub disable_file: Option<PathBuf>,
+    /// A file or stream socket for alerts about duress pins.
+    pub duress_alert: Option<PathBuf>,
+    /// Set to `1` in the PAM environment after a duress pin.
+    pub duress_env: Option<String>,
+    /// Set to `pin` in the PAM environment after a pin login.
+    pub auth_method_env: Option<String>,
+    /// Outdated hashes are replaced on a successful login.
+    pub rehash: Option<RehashTarget>,
+    pub is_debug: bool,
+    /// See [`crate::prompt::render`] for the placeholders.
+    pub prompt: String,
+}
+
+pub(crate) enum Database {
+    /// Searched in order.
+    Shared(Vec<PathBuf>),
+    /// A file owned by each user.
+    PerUser {
+        /// A leading `~/` is replaced by the user's home.
+        path: String,
+    },
+}
+
+/// Like the actions of `pam_succeed_if`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Control {
+    Ignore,
+    Fail,
+}
+
+impl FromStr for Control {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "ignore" => Ok(Self::Ignore),
+            "fail" => Ok(Self::Fail),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Overrides the return codes of some errors.
+/// Parsed apart from [`Args`], as it's needed for errors of [`Args`] as well.
+/// Still validated by [`Args`].
+#[derive(Default, Clone, Copy, Debug)]
+pub(crate) struct ReturnCodes {
+    pub unknown_user: Option<Control>,
+    /// For a missing database, including a missing file in the user's home.
+    pub missing_db: Option<Control>,
+    /// While the disable file exists.
+    pub disabled: Option<Control>,
+}
+
+impl ReturnCodes {
+    const UNKNOWN_USER_ID: &'static str = "unknown_user=";
+    const MISSING_DATABASE_ID: &'static str = "missing_db=";
+    const NULLOK_ID: &'static str = "nullok";
+    const DISABLED_ID: &'static str = "disabled=";
+
+    /// `failure` is the code of the service for `fail`.
+    pub fn return_code(&self, error: &crate::Error, failure: PamError) -> Option<PamError> {
+        let control = match error {
+            crate::Error::UnknownUser => self.unknown_user,
+            crate::Error::MissingDatabase => self.missing_db,
+            crate::Error::DisabledByFile => self.disabled,
+            _ => None,
+        }?;
+        Some(match control {
+            Control::Ignore => PamError::IGNORE,
+            Control::Fail => failure,
+        })
+    }
+}
+
+impl TryFrom<&[String]> for ReturnCodes {
+    type Error = crate::Error;
+
+    fn try_from(value: &[String]) -> Result<Self, Self::Error> {
+        let missing_db = parse_optional_named_value(value, Self::MISSING_DATABASE_ID)?;
+        let is_nullok = value.contains(&Self::NULLOK_ID.to_string());
+
+        Ok(Self {
+            unknown_user: parse_optional_named_value(value, Self::UNKNOWN_USER_ID)?,
+            missing_db: missing_db.or(is_nullok.then_some(Control::Ignore)),
+            disabled: parse_optional_named_value(value, Self::DISABLED_ID)?,
+        })
+    }
+}
+
+/// Which database defines a user, if several do.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum DatabaseMatch {
+    #[default]
+    First,
+    Last,
+}
+
+impl FromStr for DatabaseMatch {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "first" => Ok(Self::First),
+            "last" => Ok(Self::Last),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How to use a pin which a previous module has already asked for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum FirstPass {
+    Prompt,
+    /// Prompt if there is none or it doesn't match.
+    Try,
+    /// Never prompt.
+    Use,
+}
+
+/// Only active if a state directory is given.
+pub(crate) struct LockoutArgs {
+    /// `0` disables the lockout.
+    pub deny: u32,
+    /// In seconds. `0` keeps the account locked until its state is removed.
+    pub unlock_time: u64,
+    /// In seconds.
+    pub fail_interval: u64,
 }
 
 impl Args {
     const DATABASE_FILEPATH_ID: &'static str = "db=";
+    const USER_DATABASE_FILEPATH_ID: &'static str = "user_db=";
+    const DATABASE_MATCH_ID: &'static str = "db_match=";
+    const STATE_DIR_ID: &'static str = "state=";
+    const DENY_ID: &'static str = "deny=";
+    const UNLOCK_TIME_ID: &'static str = "unlock_time=";
+    const FAIL_INTERVAL_ID: &'static str = "fail_interval=";
+    const MAX_AGE_ID: &'static str = "max_age=";
+    const WARN_AGE_ID: &'static str = "warn_age=";
+    const MAX_PASSWORD_AGE_ID: &'static str = "max_password_age=";
+    const MARK_ID: &'static str = "mark";
+    const TRY_FIRST_PASS_ID: &'static str = "try_first_pass";
+    const USE_FIRST_PASS_ID: &'static str = "use_first_pass";
+    const RETRY_ID: &'static str = "retry=";
+    const FAIL_DELAY_ID: &'static str = "fail_delay=";
+    const USE_AUTHTOK_ID: &'static str = "use_authtok";
+    const REMEMBER_ID: &'static str = "remember=";
+    const PIN_CHARSET_ID: &'static str = "pin_charset=";
+    const MIN_LEN_ID: &'static str = "min_len=";
+    const MAX_LEN_ID: &'static str = "max_len=";
+    const REVEAL_UNKNOWN_USER_ID: &'static str = "reveal_unknown_user";
+    const MIN_M_COST_ID: &'static str = "min_m_cost=";
+    const MAX_M_COST_ID: &'static str = "max_m_cost=";
+    const MIN_T_COST_ID: &'static str = "min_t_cost=";
+    const MAX_T_COST_ID: &'static str = "max_t_cost=";
+    const MIN_P_COST_ID: &'static str = "min_p_cost=";
+    const MAX_P_COST_ID: &'static str = "max_p_cost=";
+    const MIN_SCRYPT_LN_ID: &'static str = "min_scrypt_ln=";
+    const MAX_SCRYPT_LN_ID: &'static str = "max_scrypt_ln=";
+    const MIN_SCRYPT_R_ID: &'static str = "min_scrypt_r=";
+    const MAX_SCRYPT_R_ID: &'static str = "max_scrypt_r=";
+    const MIN_SCRYPT_P_ID: &'static str = "min_scrypt_p=";
+    const MAX_SCRYPT_P_ID: &'static str = "max_scrypt_p=";
+    const MIN_PBKDF2_I_ID: &'static str = "min_pbkdf2_i=";
+    const MAX_PBKDF2_I_ID: &'static str = "max_pbkdf2_i=";
+    const ALLOWED_SERVICES_ID: &'static str = "allowed_services=";
+    const DENY_REMOTE_ID: &'static str = "deny_remote";
+    const ALLOWED_TTYS_ID: &'static str = "allowed_ttys=";
+    const GROUP_ID: &'static str = "group=";
+    const DENY_GROUP_ID: &'static str = "deny_group=";
+    const DISABLE_FILE_ID: &'static str = "disable_file=";
+    const DURESS_ALERT_ID: &'static str = "duress_alert=";
+    const DURESS_ENV_ID: &'static str = "duress_env=";
+    const AUTH_METHOD_ENV_ID: &'static str = "auth_method_env=";
+    const PROMPT_ID: &'static str = "prompt=";
+    const PEPPER_ID: &'static str = "pepper=";
+    const REHASH_ID: &'static str = "rehash";
+    const REHASH_ALGORITHM_ID: &'static str = "rehash_algorithm=";
+    const REHASH_M_COST_ID: &'static str = "rehash_m_cost=";
+    const REHASH_T_COST_ID: &'static str = "rehash_t_cost=";
+    const REHASH_P_COST_ID: &'static str = "rehash_p_cost=";
+
+    const DEFAULT_DENY: u32 = 3;
+    const DEFAULT_UNLOCK_TIME: u64 = 600;
+    const DEFAULT_FAIL_INTERVAL: u64 = 900;
+    const DEFAULT_WARN_AGE: u64 = 7;
+    const DEFAULT_RETRY: NonZeroU32 = NonZeroU32::MIN;
+    const DEFAULT_FAIL_DELAY: u64 = 2000;
+}
+
+fn parse_optional_named_value<T: FromStr>(
+    args: &[String],
+    key: &'static str,
+) -> Result<Option<T>, crate::Error> {
+    pam_utils::parse_named_value(args, key)
+        .transpose()
+        .map_err(|_| crate::Error::InvalidArg(key))
+}
+
+/// Comma separated
+fn extract_named_list(args: &[String], key: &str) -> Option<Vec<String>> {
+    pam_utils::extract_named_value(args, key)
+        .map(|list| list.split(',').map(ToString::to_string).collect())
+}
+
+/// For a variable of the PAM environment.
+fn extract_env_name(args: &[String], key: &'static str) -> Result<Option<String>, crate::Error> {
+    match pam_utils::extract_named_value(args, key) {
+        Some(name) if name.is_empty() || name.contains('=') => Err(crate::Error::InvalidArg(key)),
+        name => Ok(name.map(ToString::to_string)),
+    }
+}
+
+fn parse_named_value<T: FromStr>(
+    args: &[String],
+    key: &'static str,
+    default: T,
+) -> Result<T, crate::Error> {
+    pam_utils::parse_named_value(args, key)
+        .unwrap_or(Ok(default))
+        .map_err(|_| crate::Error::InvalidArg(key))
 }
 
 impl TryFrom<Vec<String>> for Args {
     type Error = crate::Error;
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        let database_filepath = pam_utils::extract_named_value(&value, Self::DATABASE_FILEPATH_ID)
-            .ok_or(crate::Error::MissingDatabaseArg)?
-            .into();
+        let database_filepaths: Vec<PathBuf> =
+            pam_utils::extract_named_values(&value, Self::DATABASE_FILEPATH_ID)
+                .flat_map(|database_filepaths| database_filepaths.split(','))
+                .filter(|database_filepath| !database_filepath.is_empty())
+                .map(Into::into)
+                .collect();
+        let database = match (
+            database_filepaths.is_empty(),
+            pam_utils::extract_named_value(&value, Self::USER_DATABASE_FILEPATH_ID),
+        ) {
+            (false, None) => Database::Shared(database_filepaths),
+            (true, Some(user_database_filepath)) => Database::PerUser {
+                path: user_database_filepath.to_string(),
+            },
+            (false, Some(_)) => {
+                return Err(crate::Error::InvalidArg(Self::USER_DATABASE_FILEPATH_ID))
+            }
+            (true, None) => return Err(crate::Error::MissingDatabaseArg),
+        };
+        let database_match =
+            parse_named_value(&value, Self::DATABASE_MATCH_ID, DatabaseMatch::default())?;
+
+        let state_dir: Option<PathBuf> =
+            pam_utils::extract_named_value(&value, Self::STATE_DIR_ID).map(Into::into);
+        let lockout = state_dir
+            .as_ref()
+            .map(|_| {
+                Ok::<_, Self::Error>(LockoutArgs {
+                    deny: parse_named_value(&value, Self::DENY_ID, Self::DEFAULT_DENY)?,
+                    unlock_time: parse_named_value(
+                        &value,
+                        Self::UNLOCK_TIME_ID,
+                        Self::DEFAULT_UNLOCK_TIME,
+                    )?,
+                    fail_interval: parse_named_value(
+                        &value,
+                        Self::FAIL_INTERVAL_ID,
+                        Self::DEFAULT_FAIL_INTERVAL,
+                    )?,
+                })
+            })
+            .transpose()?;
+
+        let max_age = parse_optional_named_value(&value, Self::MAX_AGE_ID)?;
+        let warn_age = parse_named_value(&value, Self::WARN_AGE_ID, Self::DEFAULT_WARN_AGE)?;
+
+        let max_password_age = parse_optional_named_value(&value, Self::MAX_PASSWORD_AGE_ID)?;
+        let is_mark = value.contains(&Self::MARK_ID.to_string());
+        if (max_password_age.is_some() || is_mark) && state_dir.is_none() {
+            return Err(crate::Error::InvalidArg(Self::STATE_DIR_ID));
+        }
+
+        let first_pass = if value.contains(&Self::USE_FIRST_PASS_ID.to_string()) {
+            FirstPass::Use
+        } else if value.contains(&Self::TRY_FIRST_PASS_ID.to_string()) {
+            FirstPass::Try
+        } else {
+            FirstPass::Prompt
+        };
+        let retry = parse_named_value(&value, Self::RETRY_ID, Self::DEFAULT_RETRY)?;
+        let fail_delay = parse_named_value(&value, Self::FAIL_DELAY_ID, Self::DEFAULT_FAIL_DELAY)?;
+        let use_authtok = value.contains(&Self::USE_AUTHTOK_ID.to_string());
+        let remember = parse_named_value(&value, Self::REMEMBER_ID, 0)?;
+        let pin_policy = pin_data::PinPolicy {
+            charset: parse_named_value(&value, Self::PIN_CHARSET_ID, Default::default())?,
+            min_len: parse_optional_named_value(&value, Self::MIN_LEN_ID)?,
+            max_len: parse_optional_named_value(&value, Self::MAX_LEN_ID)?,
+        };
+        if let (Some(min_len), Some(max_len)) = (pin_policy.min_len, pin_policy.max_len) {
+            if min_len > max_len {
+                return Err(crate::Error::InvalidArg(Self::MAX_LEN_ID));
+            }
+        }
+        let return_codes = ReturnCodes::try_from(&value[..])?;
+        // Ignoring unknown users reveals them anyway
+        let conceal_unknown_user = !value.contains(&Self::REVEAL_UNKNOWN_USER_ID.to_string())
+            && return_codes.unknown_user != Some(Control::Ignore);
+
+        let cost_limit = |min_id, max_id| {
+            Ok::<_, Self::Error>(CostLimit {
+                min: parse_optional_named_value(&value, min_id)?,
+                max: parse_optional_named_value(&value, max_id)?,
+            })
+        };
+        let cost_limits = CostLimits {
+            m_cost: cost_limit(Self::MIN_M_COST_ID, Self::MAX_M_COST_ID)?,
+            t_cost: cost_limit(Self::MIN_T_COST_ID, Self::MAX_T_COST_ID)?,
+            p_cost: cost_limit(Self::MIN_P_COST_ID, Self::MAX_P_COST_ID)?,
+            scrypt_ln: cost_limit(Self::MIN_SCRYPT_LN_ID, Self::MAX_SCRYPT_LN_ID)?,
+            scrypt_r: cost_limit(Self::MIN_SCRYPT_R_ID, Self::MAX_SCRYPT_R_ID)?,
+            scrypt_p: cost_limit(Self::MIN_SCRYPT_P_ID, Self::MAX_SCRYPT_P_ID)?,
+            pbkdf2_i: cost_limit(Self::MIN_PBKDF2_I_ID, Self::MAX_PBKDF2_I_ID)?,
+        };
+
+        let restrictions = Restrictions {
+            allowed_services: extract_named_list(&value, Self::ALLOWED_SERVICES_ID),
+            deny_remote: value.contains(&Self::DENY_REMOTE_ID.to_string()),
+            allowed_ttys: extract_named_list(&value, Self::ALLOWED_TTYS_ID),
+        };
+        // Next to the first shared database, as a user's home is no place for a global switch
+        let disable_file = pam_utils::extract_named_value(&value, Self::DISABLE_FILE_ID)
+            .map(Into::into)
+            .or_else(|| match &database {
+                Database::Shared(paths) => paths
+                    .first()
+                    .map(|path| pin_data::default_disable_file(path)),
+                Database::PerUser { .. } => None,
+            });
+        let group_policy = GroupPolicy {
+            groups: extract_named_list(&value, Self::GROUP_ID),
+            deny_groups: extract_named_list(&value, Self::DENY_GROUP_ID).unwrap_or_default(),
+        };
+
+        let duress_alert =
+            pam_utils::extract_named_value(&value, Self::DURESS_ALERT_ID).map(Into::into);
+        let duress_env = extract_env_name(&value, Self::DURESS_ENV_ID)?;
+        let auth_method_env = extract_env_name(&value, Self::AUTH_METHOD_ENV_ID)?;
+
+        let rehash = value
+            .contains(&Self::REHASH_ID.to_string())
+            .then(|| {
+                let argon2_params = argon2::Params::new(
+                    parse_named_value(
+                        &value,
+                        Self::REHASH_M_COST_ID,
+                        argon2::Params::DEFAULT_M_COST,
+                    )?,
+                    parse_named_value(
+                        &value,
+                        Self::REHASH_T_COST_ID,
+                        argon2::Params::DEFAULT_T_COST,
+                    )?,
+                    parse_named_value(
+                        &value,
+                        Self::REHASH_P_COST_ID,
+                        argon2::Params::DEFAULT_P_COST,
+                    )?,
+                    None,
+                )
+                .map_err(|_| crate::Error::InvalidArg(Self::REHASH_ID))?;
+
+                Ok(RehashTarget {
+                    algorithm: parse_named_value(
+                        &value,
+                        Self::REHASH_ALGORITHM_ID,
+                        Default::default(),
+                    )?,
+                    argon2_params,
+                })
+            })
+            .transpose()?;
+        let is_debug = pam_utils::is_debug(&value);
+        let prompt = pam_utils::extract_named_value(&value, Self::PROMPT_ID)
+            .unwrap_or(crate::prompt::DEFAULT_PROMPT)
+            .to_string();
 
-        Ok(Self { database_filepath })
+        Ok(Self {
+            database,
+            database_match,
+            state_dir,
+            lockout,
+            max_password_age,
+            is_mark,
+            max_age,
+            warn_age,
+            first_pass,
+            retry,
+            fail_delay,
+            use_authtok,
+            remember,
+            pin_policy,
+            conceal_unknown_user,
+            cost_limits,
+            pepper: pam_utils::extract_named_value(&value, Self::PEPPER_ID).map(Into::into),
+            restrictions,
+            group_policy,
+            disable_file,
+            duress_alert,
+            duress_env,
+            auth_method_env,
+            rehash,
+            is_debug,
+            prompt,
+        })
     }
 }
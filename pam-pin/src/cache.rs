@@ -0,0 +1,109 @@
+//! Keeps parsed databases across calls, as e.g. display managers
+//! load the module once and authenticate many times.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Limits the memory for per-user files.
+const MAX_ENTRIES: usize = 16;
+
+/// The handler runs on a new sandboxed thread for each call, so the cache is process-wide.
+/// It's only locked to look up or insert, never while reading or parsing.
+static CACHE: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+struct Entry {
+    path: PathBuf,
+    version: FileVersion,
+    data: pin_data::Data,
+}
+
+/// Changes when the file is replaced or written to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct FileVersion {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl From<&std::fs::Metadata> for FileVersion {
+    fn from(metadata: &std::fs::Metadata) -> Self {
+        Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.size(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+        }
+    }
+}
+
+pub(crate) fn get(path: &Path, version: FileVersion) -> Option<pin_data::Data> {
+    lock()
+        .iter()
+        .find(|entry| entry.path == path && entry.version == version)
+        .map(|entry| entry.data.clone())
+}
+
+/// Replaces an older version of the file.
+pub(crate) fn insert(path: &Path, version: FileVersion, data: &pin_data::Data) {
+    let mut cache = lock();
+    cache.retain(|entry| entry.path != path);
+    if cache.len() >= MAX_ENTRIES {
+        cache.remove(0);
+    }
+    cache.push(Entry {
+        path: path.to_path_buf(),
+        version,
+        data: data.clone(),
+    });
+}
+
+/// A panicking sandboxed thread can poison the lock.
+/// The entries are only replaced as a whole, so they're still consistent.
+fn lock() -> MutexGuard<'static, Vec<Entry>> {
+    CACHE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DATA: &str = r#"
+        [[users]]
+        name = "user"
+        pin_hash = "$argon2d$v=19$m=4096,t=3,p=1$PFRID+hbQKjEFESZWQZMEA$mMpICfZn5N0bV13RJ3nWYfYXesgTJcPl81xwrqzDDLY"
+    "#;
+
+    fn version(ino: u64, mtime: i64) -> FileVersion {
+        FileVersion {
+            dev: 1,
+            ino,
+            size: DATA.len() as u64,
+            mtime: (mtime, 0),
+            ctime: (mtime, 0),
+        }
+    }
+
+    #[test]
+    fn reuse_only_unchanged_files() {
+        let path = Path::new("/cache-test/pins.toml");
+        let data = pin_data::Data::from_toml(DATA).unwrap();
+
+        assert!(get(path, version(1, 100)).is_none());
+        insert(path, version(1, 100), &data);
+        assert!(get(path, version(1, 100))
+            .unwrap()
+            .get_by_name("user")
+            .is_some());
+        assert!(get(path, version(1, 101)).is_none());
+        assert!(get(path, version(2, 100)).is_none());
+        assert!(get(Path::new("/cache-test/other.toml"), version(1, 100)).is_none());
+
+        insert(path, version(2, 100), &data);
+        assert!(get(path, version(1, 100)).is_none());
+        assert!(get(path, version(2, 100)).is_some());
+    }
+}
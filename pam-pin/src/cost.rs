@@ -0,0 +1,138 @@
+use crate::{Error, Result};
+use argon2::password_hash::PasswordHash;
+use error_stack::{Report, ResultExt};
+
+#[derive(Default, Clone, Copy, Debug)]
+pub(crate) struct CostLimit {
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+}
+
+/// Bounds for the parameters of the database entries, by algorithm.
+#[derive(Default, Clone, Copy, Debug)]
+pub(crate) struct CostLimits {
+    pub m_cost: CostLimit,
+    pub t_cost: CostLimit,
+    pub p_cost: CostLimit,
+    pub scrypt_ln: CostLimit,
+    pub scrypt_r: CostLimit,
+    pub scrypt_p: CostLimit,
+    pub pbkdf2_i: CostLimit,
+}
+
+impl CostLimits {
+    /// Has to be called before verifying, as the hash could demand any amount of resources.
+    pub fn check(&self, hash: &PasswordHash<'_>) -> Result<()> {
+        self.check_limits(hash, true)
+    }
+
+    /// Only the maximums, e.g. for old pins, which are verified but never accepted.
+    pub fn check_max(&self, hash: &PasswordHash<'_>) -> Result<()> {
+        self.check_limits(hash, false)
+    }
+
+    fn check_limits(&self, hash: &PasswordHash<'_>, is_min_checked: bool) -> Result<()> {
+        let algorithm = hash.algorithm.as_str();
+        let limits = if algorithm.starts_with("argon2") {
+            vec![("m", self.m_cost), ("t", self.t_cost), ("p", self.p_cost)]
+        } else if algorithm == "scrypt" {
+            vec![
+                ("ln", self.scrypt_ln),
+                ("r", self.scrypt_r),
+                ("p", self.scrypt_p),
+            ]
+        } else if algorithm.starts_with("pbkdf2") {
+            vec![("i", self.pbkdf2_i)]
+        } else {
+            Vec::new()
+        };
+
+        for (name, limit) in limits {
+            let Some(value) = hash.params.get_decimal(name) else {
+                continue;
+            };
+            if limit.max.is_some_and(|max| value > max) {
+                return Err(Report::new(Error::CostTooHigh))
+                    .attach_printable(format!("{}={}", name, value));
+            }
+            if is_min_checked && limit.min.is_some_and(|min| value < min) {
+                return Err(Report::new(Error::CostTooLow))
+                    .attach_printable(format!("{}={}", name, value));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "$argon2d$v=19$m=4096,t=3,p=1$PFRID+hbQKjEFESZWQZMEA$mMpICfZn5N0bV13RJ3nWYfYXesgTJcPl81xwrqzDDLY";
+
+    #[test]
+    fn accept_within_limits() {
+        let limits = CostLimits {
+            m_cost: CostLimit {
+                min: Some(4096),
+                max: Some(4096),
+            },
+            ..Default::default()
+        };
+        limits.check(&PasswordHash::new(HASH).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn reject_outside_limits() {
+        let hash = PasswordHash::new(HASH).unwrap();
+
+        let limits = CostLimits {
+            m_cost: CostLimit {
+                min: None,
+                max: Some(1024),
+            },
+            ..Default::default()
+        };
+        let report = limits.check(&hash).unwrap_err();
+        assert!(matches!(report.current_context(), Error::CostTooHigh));
+
+        let limits = CostLimits {
+            t_cost: CostLimit {
+                min: Some(4),
+                max: None,
+            },
+            ..Default::default()
+        };
+        let report = limits.check(&hash).unwrap_err();
+        assert!(matches!(report.current_context(), Error::CostTooLow));
+        limits.check_max(&hash).unwrap();
+    }
+
+    #[test]
+    fn check_limits_of_the_algorithm() {
+        let scrypt = PasswordHash::new("$scrypt$ln=17,r=8,p=1$UkZ4QWdQYlFBU3lXWk9lbw$cRYHK4cH6yX5w7D/SyZVlnVzJ3dNhVSg9UlxoyqUxzY").unwrap();
+        let pbkdf2 = PasswordHash::new("$pbkdf2-sha256$i=600000,l=32$UkZ4QWdQYlFBU3lXWk9lbw$cRYHK4cH6yX5w7D/SyZVlnVzJ3dNhVSg9UlxoyqUxzY").unwrap();
+        let limits = CostLimits {
+            p_cost: CostLimit {
+                min: None,
+                max: Some(0),
+            },
+            scrypt_ln: CostLimit {
+                min: None,
+                max: Some(16),
+            },
+            pbkdf2_i: CostLimit {
+                min: Some(1_000_000),
+                max: None,
+            },
+            ..Default::default()
+        };
+
+        let report = limits.check(&scrypt).unwrap_err();
+        assert!(matches!(report.current_context(), Error::CostTooHigh));
+        let report = limits.check(&pbkdf2).unwrap_err();
+        assert!(matches!(report.current_context(), Error::CostTooLow));
+        let report = limits.check(&PasswordHash::new(HASH).unwrap()).unwrap_err();
+        assert!(matches!(report.current_context(), Error::CostTooHigh));
+    }
+}
@@ -0,0 +1,222 @@
+use crate::args::{Database, DatabaseMatch};
+use crate::{cache, Error, Result};
+use error_stack::{Report, ResultExt};
+use pamsm::PamError;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// The database files for one user.
+pub(crate) struct Databases {
+    pub paths: Vec<PathBuf>,
+    /// Set for a file in the user's home, which is the only path then.
+    user_file: Option<pam_utils::PrivateFile>,
+    /// The secret of the hashes in the files.
+    pub pepper: Option<pin_data::Pepper>,
+    database_match: DatabaseMatch,
+}
+
+/// The file, which defines the user.
+/// Without a match it's the last file.
+pub(crate) struct LoadedDatabase {
+    path: PathBuf,
+    pub data: pin_data::Data,
+}
+
+impl Databases {
+    /// Has to be called before the sandbox is active, as it may ask NSS for the home directory.
+    pub fn resolve(
+        database: &Database,
+        database_match: DatabaseMatch,
+        user_name: &str,
+    ) -> Result<Self> {
+        match database {
+            Database::Shared(paths) => Ok(Self {
+                paths: paths.clone(),
+                user_file: None,
+                pepper: None,
+                database_match,
+            }),
+            Database::PerUser { path, .. } => {
+                let passwd = pam_utils::get_passwd(user_name)
+                    .change_context(Error::LoadDatabase)?
+                    .ok_or(Error::UnknownUser)
+                    .attach(PamError::USER_UNKNOWN)?;
+                let path = expand_home(path, &passwd.home);
+                let user_file = match pam_utils::PrivateFile::new(&path, passwd) {
+                    Ok(user_file) => user_file,
+                    // Like a missing file
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                        return Ok(Self {
+                            paths: Vec::new(),
+                            user_file: None,
+                            pepper: None,
+                            database_match,
+                        })
+                    }
+                    Err(error) => {
+                        return Err(Report::new(error).change_context(Error::LoadDatabase))
+                            .attach_printable(path.display().to_string())
+                    }
+                };
+                Ok(Self {
+                    paths: vec![path],
+                    user_file: Some(user_file),
+                    pepper: None,
+                    database_match,
+                })
+            }
+        }
+    }
+
+    /// Missing files are skipped.
+    /// Returns `None` if all are missing.
+    pub fn load(&self, user_name: &str) -> Result<Option<LoadedDatabase>> {
+        let mut loaded = None;
+
+        for path in &self.paths {
+            let Some(data) = self.load_file(path)? else {
+                continue;
+            };
+            let is_match = data.get_by_name(user_name).is_some();
+            let was_match = loaded.as_ref().is_some_and(|loaded: &LoadedDatabase| {
+                loaded.data.get_by_name(user_name).is_some()
+            });
+
+            if !was_match || (is_match && self.database_match == DatabaseMatch::Last) {
+                loaded = Some(LoadedDatabase {
+                    path: path.clone(),
+                    data,
+                });
+            }
+            if is_match && self.database_match == DatabaseMatch::First {
+                break;
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Reuses the parsed data of an unchanged file from a previous call.
+    fn load_file(&self, path: &Path) -> Result<Option<pin_data::Data>> {
+        let file = match &self.user_file {
+            Some(user_file) => user_file.open(),
+            None => File::open(path),
+        };
+        let read_error = |error| {
+            Report::new(error)
+                .change_context(Error::LoadDatabase)
+                .attach_printable(path.display().to_string())
+        };
+        let mut file = match file {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(read_error(error)),
+        };
+
+        // Of the opened file, so it can't be swapped in between
+        let version = cache::FileVersion::from(&file.metadata().map_err(read_error)?);
+        if let Some(data) = cache::get(path, version) {
+            return Ok(Some(data));
+        }
+
+        let mut data_string = String::new();
+        file.read_to_string(&mut data_string).map_err(read_error)?;
+        let data = pin_data::Data::from_toml(&data_string).change_context(Error::LoadDatabase)?;
+        cache::insert(path, version, &data);
+        Ok(Some(data))
+    }
+
+    /// Changes the user's entry while other writers wait, e.g. `pin-gen` or another login,
+    /// so their changes aren't undone.
+    /// `change` gets the current entry, which can differ from a previously loaded one,
+    /// and returns whether it changed it.
+    pub fn update(
+        &self,
+        user_name: &str,
+        change: impl FnOnce(&mut pin_data::User) -> Result<bool>,
+    ) -> Result<()> {
+        let mut loaded = self.load(user_name)?.ok_or(Error::MissingDatabase)?;
+        let _lock = self.lock(&loaded.path)?;
+        // Another writer could have replaced it before the lock
+        loaded.data = self
+            .load_file(&loaded.path)?
+            .ok_or(Error::MissingDatabase)?;
+        let user = loaded
+            .data
+            .get_by_name_mut(user_name)
+            .ok_or(Error::UnknownUser)?;
+
+        if change(user)? {
+            self.write(&loaded)?;
+        }
+        Ok(())
+    }
+
+    fn lock(&self, path: &Path) -> Result<pin_data::FileLock> {
+        match &self.user_file {
+            Some(user_file) => pin_data::FileLock::acquire(|| user_file.open()),
+            None => pin_data::FileLock::acquire(|| File::open(path)),
+        }
+        .map_err(|error| {
+            Report::new(error)
+                .change_context(Error::WriteDatabase)
+                .attach_printable(path.display().to_string())
+        })
+    }
+
+    fn write(&self, loaded: &LoadedDatabase) -> Result<()> {
+        match &self.user_file {
+            Some(user_file) => {
+                let data = loaded.data.to_toml().change_context(Error::WriteDatabase)?;
+                user_file
+                    .replace(data.as_bytes())
+                    .change_context(Error::WriteDatabase)
+                    .attach_printable(loaded.path.display().to_string())
+            }
+            None => loaded
+                .data
+                .write_to_file(&loaded.path)
+                .change_context(Error::WriteDatabase),
+        }
+    }
+}
+
+#[cfg(feature = "sandbox")]
+pub(crate) fn parent_dir(path: &Path) -> Result<PathBuf> {
+    let mut database_parent = path
+        .parent()
+        .ok_or(Error::Sandbox)
+        .attach_printable("Couldn't get the parent directory of the database")?
+        .to_path_buf();
+    if database_parent.as_os_str().is_empty() {
+        database_parent = ".".into();
+    }
+    Ok(database_parent)
+}
+
+/// Replaces a leading `~/`.
+fn expand_home(path: &str, home: &Path) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(relative_path) => home.join(relative_path),
+        None => path.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expand_home_only_at_start() {
+        let home = Path::new("/home/user");
+
+        assert_eq!(
+            expand_home("~/.config/pam-pin/pin.toml", home),
+            Path::new("/home/user/.config/pam-pin/pin.toml")
+        );
+        assert_eq!(
+            expand_home("/var/lib/pins/~/pin.toml", home),
+            Path::new("/var/lib/pins/~/pin.toml")
+        );
+    }
+}
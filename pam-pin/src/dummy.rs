@@ -0,0 +1,52 @@
+use argon2::password_hash::{Output, PasswordHash, PasswordHashString};
+use argon2::Params;
+
+fn has_same_parameters(hash: &PasswordHash<'_>, other: &PasswordHash<'_>) -> bool {
+    hash.algorithm == other.algorithm
+        && hash.version == other.version
+        && hash.params.as_str() == other.params.as_str()
+}
+
+/// A hash no pin matches, with the most common parameters of the database.
+/// Verifying against it takes as long as verifying a real pin.
+/// Without a usable hash in the database, it has the default parameters of `pin-gen`.
+pub(crate) fn typical_hash(data: &pin_data::Data) -> PasswordHashString {
+    most_common_hash(data).unwrap_or_else(default_hash)
+}
+
+fn most_common_hash(data: &pin_data::Data) -> Option<PasswordHashString> {
+    let mut parameter_counts: Vec<(PasswordHash<'_>, usize)> = Vec::new();
+    for user in data.users() {
+        let hash = user.pin_hash();
+        match parameter_counts
+            .iter_mut()
+            .find(|(typical_hash, _)| has_same_parameters(typical_hash, &hash))
+        {
+            Some((_, count)) => *count += 1,
+            None => parameter_counts.push((hash, 1)),
+        }
+    }
+
+    let (mut typical_hash, _) = parameter_counts
+        .into_iter()
+        .max_by_key(|(_, count)| *count)?;
+    let output_length = typical_hash.hash?.len();
+    typical_hash.hash = Some(Output::new(&vec![0; output_length]).ok()?);
+
+    Some(typical_hash.serialize())
+}
+
+/// Like a hash of `pin-gen` without any parameters given.
+fn default_hash() -> PasswordHashString {
+    let params = Params::default();
+    // A zero salt of 16 bytes and output of 32 bytes
+    let hash = format!(
+        "$argon2d$v=19$m={},t={},p={}${}${}",
+        params.m_cost(),
+        params.t_cost(),
+        params.p_cost(),
+        "A".repeat(22),
+        "A".repeat(43)
+    );
+    PasswordHashString::new(&hash).expect("The default hash is a valid PHC string")
+}
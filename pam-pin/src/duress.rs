@@ -0,0 +1,57 @@
+use crate::access::Origin;
+use crate::{Error, Result};
+use error_stack::ResultExt;
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+/// One line per alert.
+fn record(user_name: &str, origin: Origin<'_>, now: u64) -> String {
+    format!(
+        "{} duress user={:?} service={:?} tty={:?} rhost={:?}\n",
+        now,
+        user_name,
+        origin.service.unwrap_or_default(),
+        origin.tty.unwrap_or_default(),
+        origin.rhost.unwrap_or_default(),
+    )
+}
+
+/// Sends the record to a listening stream socket or appends it to a file.
+pub(crate) fn alert(path: &Path, user_name: &str, origin: Origin<'_>) -> Result<()> {
+    let record = record(user_name, origin, pin_data::unix_now());
+    let alert_error = || Error::Alert(path.to_path_buf());
+
+    let is_socket = std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+    if is_socket {
+        UnixStream::connect(path)
+            .and_then(|mut socket| socket.write_all(record.as_bytes()))
+            .change_context_lazy(alert_error)
+    } else {
+        std::fs::File::options()
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(record.as_bytes()))
+            .change_context_lazy(alert_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quote_record_fields() {
+        let origin = Origin {
+            service: Some("sudo"),
+            tty: Some("/dev/pts/0"),
+            rhost: None,
+        };
+
+        assert_eq!(
+            record("user x=1", origin, 42),
+            "42 duress user=\"user x=1\" service=\"sudo\" tty=\"/dev/pts/0\" rhost=\"\"\n"
+        );
+    }
+}
@@ -1,13 +1,29 @@
 #[macro_use]
 extern crate pamsm;
 
+mod access;
+mod account;
 mod args;
+mod cache;
+mod cost;
+mod database;
+mod dummy;
+mod duress;
+mod lockout;
+mod password_login;
+mod prompt;
+mod rehash;
 
-use argon2::{password_hash, Argon2};
+use argon2::password_hash;
 use error_stack::{Report, ResultExt};
+use pam_utils::{translate, Catalog};
 use pamsm::{Pam, PamError, PamFlags, PamLibExt, PamServiceModule};
 use password_hash::PasswordHash;
-use std::ffi::CStr;
+use pin_data::state::StateStore;
+use std::ffi::{c_int, CStr, CString};
+
+/// `PAM_UPDATE_AUTHTOK` of `<security/_pam_types.h>`, pamsm has no flag for it.
+const PAM_UPDATE_AUTHTOK: c_int = 0x2000;
 
 #[derive(thiserror::Error, Debug, Clone)]
 enum Error {
@@ -15,6 +31,8 @@ enum Error {
     SandboxPanic,
     #[error("There is no `db=/<file>` given.")]
     MissingDatabaseArg,
+    #[error("The pin database doesn't exist")]
+    MissingDatabase,
     #[error("Couldn't build sandbox")]
     Sandbox,
     #[error("Internal PAM error")]
@@ -27,6 +45,58 @@ enum Error {
     ReadPassword,
     #[error("Couldn't verify password")]
     VerifyPassword,
+    #[error("The value of `{0}<value>` is invalid.")]
+    InvalidArg(&'static str),
+    #[error("Couldn't load the authentication state")]
+    LoadState,
+    #[error("Couldn't store the authentication state")]
+    StoreState,
+    #[error("Too many failed attempts, try again later")]
+    Locked,
+    #[error("The pins don't match")]
+    PinMismatch,
+    #[error("The pin was used before")]
+    PinReused,
+    #[error("The pin has to differ from the duress pin")]
+    SameDuressPin,
+    #[error("The pin doesn't meet the requirements")]
+    PinPolicy,
+    #[error("Couldn't hash password")]
+    HashPassword,
+    #[error("Couldn't load the pepper")]
+    LoadPepper,
+    #[error("The pepper doesn't have the key of the pin")]
+    MissingPepperKey,
+    #[error("Couldn't write to database")]
+    WriteDatabase,
+    #[error("The pin is disabled")]
+    Disabled,
+    #[error("The pin has expired")]
+    Expired,
+    #[error("The session wasn't authenticated with a pin")]
+    NotPinLogin,
+    #[error("The pin is too old and has to be changed")]
+    ChangeRequired,
+    #[error("The hash parameters exceed the allowed resources")]
+    CostTooHigh,
+    #[error("The hash parameters are weaker than allowed")]
+    CostTooLow,
+    #[error("The pin isn't allowed for this service")]
+    AccessDenied,
+    #[error("Couldn't look up the groups of the user")]
+    Groups,
+    #[error("The user's groups exclude pins")]
+    ExcludedByGroup,
+    #[error("Pins are disabled on this host")]
+    DisabledByFile,
+    #[error("Log in with your password first")]
+    PasswordRequired,
+    #[error("Couldn't unseal the password")]
+    UnsealPassword,
+    #[error("Couldn't seal the password")]
+    SealPassword,
+    #[error("Couldn't raise the duress alert at '{}'", .0.display())]
+    Alert(std::path::PathBuf),
 }
 
 type Result<T> = error_stack::Result<T, Error>;
@@ -34,18 +104,82 @@ type Result<T> = error_stack::Result<T, Error>;
 struct PamPin;
 
 impl PamPin {
+    /// The sandbox is set up afterwards, as the database can be in the user's home.
+    fn open_database(
+        args: &args::Args,
+        user_name: &str,
+        is_database_writeable: bool,
+    ) -> Result<database::Databases> {
+        // Before the database, so it isn't even read for excluded users
+        args.group_policy.check(user_name)?;
+        let mut database =
+            database::Databases::resolve(&args.database, args.database_match, user_name)?;
+        // Only root can read it, so it's loaded before the sandbox
+        database.pepper = args
+            .pepper
+            .as_ref()
+            .map(|pepper| pin_data::Pepper::from_file(pepper))
+            .transpose()
+            .change_context(Error::LoadPepper)?;
+
+        #[cfg(feature = "sandbox")]
+        Self::setup_sandbox(args, &database, is_database_writeable)?;
+        #[cfg(not(feature = "sandbox"))]
+        let _ = is_database_writeable;
+
+        Ok(database)
+    }
+
     #[cfg(feature = "sandbox")]
-    fn setup_sandbox(args: &args::Args) -> Result<()> {
+    fn setup_sandbox(
+        args: &args::Args,
+        database: &database::Databases,
+        is_database_writeable: bool,
+    ) -> Result<()> {
         use birdcage::{Birdcage, Sandbox};
 
         let mut birdcage = Birdcage::new()
             .change_context(Error::Sandbox)
             .attach_printable("Initialization failed")?;
 
-        birdcage
-            .add_exception(birdcage::Exception::Read(args.database_filepath.clone()))
-            .change_context(Error::Sandbox)
-            .attach_printable("Couldn't set the database file as readable")?;
+        for path in &database.paths {
+            // Missing files are skipped when loading
+            let is_missing = !path.exists();
+            if is_database_writeable && !is_missing {
+                // The database gets replaced by a new file in the same directory
+                birdcage
+                    .add_exception(birdcage::Exception::Write(database::parent_dir(path)?))
+                    .change_context(Error::Sandbox)
+                    .attach_printable("Couldn't set the database directory as writeable")?;
+            } else if !is_missing {
+                birdcage
+                    .add_exception(birdcage::Exception::Read(path.clone()))
+                    .change_context(Error::Sandbox)
+                    .attach_printable("Couldn't set the database file as readable")?;
+            }
+        }
+
+        if let Some(state_dir) = &args.state_dir {
+            birdcage
+                .add_exception(birdcage::Exception::Write(state_dir.clone()))
+                .change_context(Error::Sandbox)
+                .attach_printable("Couldn't set the state directory as writeable")?;
+        }
+
+        // The sandbox can't allow a missing path, which mustn't break every login
+        match &args.duress_alert {
+            Some(duress_alert) if duress_alert.exists() => {
+                birdcage
+                    .add_exception(birdcage::Exception::Write(duress_alert.clone()))
+                    .change_context(Error::Sandbox)
+                    .attach_printable("Couldn't set the duress alert as writeable")?;
+            }
+            Some(duress_alert) => pam_utils::log_notice(&format!(
+                "pam-pin: The duress alert {} doesn't exist",
+                duress_alert.display()
+            )),
+            None => {}
+        }
 
         birdcage
             .lock()
@@ -53,42 +187,747 @@ impl PamPin {
             .attach_printable("Couldn't activate sandbox")
     }
 
-    fn get_user_pin(pamh: &Pam) -> Result<&CStr> {
-        pamh.conv(Some("Pin: "), pamsm::PamMsgStyle::PROMPT_ECHO_OFF)
+    fn get_user_pin(pamh: &Pam, prompt: &str) -> Result<CString> {
+        // Copied, as the next conversation could free the answer
+        pamh.conv(Some(prompt), pamsm::PamMsgStyle::PROMPT_ECHO_OFF)
             .map_err(|pam_code| Report::new(Error::Pam).attach(pam_code))?
+            .map(CStr::to_owned)
             .ok_or(Error::ReadPassword)
             .attach(PamError::AUTHTOK_RECOVERY_ERR)
     }
 
-    fn verify_pin(hash: PasswordHash<'_>, pin: &[u8]) -> Result<()> {
-        hash.verify_password(&[&Argon2::default()], pin)
-            .change_context(Error::VerifyPassword)
+    /// `authtok` is a token item read through `PamLibExt`.
+    fn get_cached_pin(
+        first_pass: args::FirstPass,
+        authtok: impl FnOnce() -> pamsm::PamResult<Option<CString>>,
+    ) -> Result<Option<CString>> {
+        if first_pass == args::FirstPass::Prompt {
+            return Ok(None);
+        }
+        authtok().map_err(|pam_code| Report::new(Error::Pam).attach(pam_code))
     }
 
-    fn auth(pamh: &Pam, _flags: PamFlags, args: Vec<String>) -> Result<()> {
+    /// Makes the pin available to the following modules.
+    fn store_pin(pamh: &Pam, pin: &CString) -> Result<()> {
+        pamh.set_authtok(pin)
+            .map_err(|pam_code| Report::new(Error::Pam).attach(pam_code))
+    }
+
+    /// Returns `None` if the user has to be prompted.
+    fn verify_first_pass(
+        first_pass: args::FirstPass,
+        cached_pin: Option<CString>,
+        verify: impl FnOnce(&CStr) -> Result<()>,
+    ) -> Option<Result<()>> {
+        match (first_pass, cached_pin) {
+            (args::FirstPass::Use, None) => {
+                Some(Err(Report::new(Error::ReadPassword)).attach(PamError::AUTHTOK_RECOVERY_ERR))
+            }
+            (args::FirstPass::Use, Some(pin)) => Some(verify(&pin)),
+            (args::FirstPass::Try, Some(pin)) => verify(&pin).ok().map(Ok),
+            (args::FirstPass::Try | args::FirstPass::Prompt, None) => None,
+            (args::FirstPass::Prompt, Some(_)) => None,
+        }
+    }
+
+    /// Checked before prompting.
+    fn check_restrictions(
+        pamh: &Pam,
+        args: &args::Args,
+        user: Option<&pin_data::User>,
+    ) -> Result<()> {
+        args.restrictions.for_user(user).check(Self::origin(pamh)?)
+    }
+
+    fn origin(pamh: &Pam) -> Result<access::Origin<'_>> {
+        fn item(item: pamsm::PamResult<Option<&CStr>>) -> Result<Option<&str>> {
+            item.map_err(|pam_code| Report::new(Error::Pam).attach(pam_code))
+                .map(|item| item.and_then(|item| item.to_str().ok()))
+        }
+        Ok(access::Origin {
+            service: item(pamh.get_service())?,
+            tty: item(pam_utils::get_tty(pamh))?,
+            rhost: item(pamh.get_rhost())?,
+        })
+    }
+
+    fn verify_pin(
+        hash: PasswordHash<'_>,
+        pin: &[u8],
+        pepper: Option<&pin_data::Pepper>,
+    ) -> Result<()> {
+        pin_data::verify_pin(&hash, pin, pepper).change_context(Error::VerifyPassword)
+    }
+
+    fn load_lockout<'a>(
+        args: &'a args::Args,
+        user_name: &'a str,
+    ) -> Result<Option<lockout::Lockout<'a>>> {
+        let lockout = args
+            .lockout
+            .as_ref()
+            .zip(args.state_dir.as_ref())
+            .map(|(lockout_args, state_dir)| {
+                lockout::Lockout::load(lockout_args, StateStore::new(state_dir), user_name)
+            })
+            .transpose()?;
+        if let Some(lockout) = &lockout {
+            lockout.ensure_unlocked()?;
+        }
+        Ok(lockout)
+    }
+
+    /// Returns whether the pin matched the duress hash.
+    fn verify_counted(
+        args: &args::Args,
+        lockout: &mut Option<lockout::Lockout<'_>>,
+        pepper: Option<&pin_data::Pepper>,
+        hash: PasswordHash<'_>,
+        duress_hash: Option<PasswordHash<'_>>,
+        pin: &CStr,
+    ) -> Result<bool> {
+        // Held until the attempt is recorded, so parallel attempts can't exceed the limit
+        let _state_lock = lockout.as_mut().map(lockout::Lockout::lock).transpose()?;
+        if let Some(lockout) = lockout {
+            lockout.ensure_unlocked()?;
+        }
+        // A missing key isn't a wrong pin
+        for hash in std::iter::once(&hash).chain(&duress_hash) {
+            args.cost_limits.check(hash)?;
+            if !pin_data::has_pepper_key(hash, pepper) {
+                return Err(Error::MissingPepperKey.into());
+            }
+        }
+        let verification = match args.pin_policy.check(pin.to_bytes()) {
+            Ok(()) => Self::verify_pin_or_duress(hash, duress_hash, pin.to_bytes(), pepper),
+            // Can't match, so the hashing is skipped
+            Err(error) => Err(Report::new(error).change_context(Error::VerifyPassword)),
+        };
+        if let Some(lockout) = lockout {
+            lockout.record(verification.is_ok())?;
+        }
+        verification
+    }
+
+    /// Both hashes are always verified, so the timing doesn't tell which one matched.
+    fn verify_pin_or_duress(
+        hash: PasswordHash<'_>,
+        duress_hash: Option<PasswordHash<'_>>,
+        pin: &[u8],
+        pepper: Option<&pin_data::Pepper>,
+    ) -> Result<bool> {
+        let verification = Self::verify_pin(hash, pin, pepper);
+        let duress_verification =
+            duress_hash.map(|duress_hash| Self::verify_pin(duress_hash, pin, pepper));
+
+        match (verification, duress_verification) {
+            (Ok(()), _) => Ok(false),
+            (Err(_), Some(Ok(()))) => Ok(true),
+            (Err(report), _) => Err(report),
+        }
+    }
+
+    fn auth(
+        pamh: &Pam,
+        flags: PamFlags,
+        args: Vec<String>,
+        catalog: Option<&Catalog>,
+    ) -> Result<()> {
         let args = args::Args::try_from(args).attach(PamError::IGNORE)?;
+        // Marking a password login isn't a pin login
+        if !args.is_mark {
+            Self::check_disable_file(&args)?;
+        }
 
-        #[cfg(feature = "sandbox")]
-        Self::setup_sandbox(&args)?;
+        let user_name = pam_utils::get_username(pamh, Error::Pam, Error::UnknownUser)?;
+        let database = Self::open_database(&args, &user_name, args.rehash.is_some())?;
+        let loaded_database = database
+            .load(&user_name)?
+            .ok_or(Error::MissingDatabase)
+            .attach(PamError::AUTH_ERR)?;
+        let users_data = &loaded_database.data;
+        let user = users_data.get_by_name(&user_name);
+        if let (true, Some(state_dir)) = (args.is_mark, &args.state_dir) {
+            // Users without a pin don't need the timestamp
+            if user.is_some() {
+                password_login::mark(&StateStore::new(state_dir), &user_name)
+                    .attach(PamError::IGNORE)?;
+            }
+            return Ok(());
+        }
+        Self::check_restrictions(pamh, &args, user)?;
+        let user = match user {
+            Some(user) => user,
+            None if args.conceal_unknown_user => {
+                let prompt = prompt::render(translate(catalog, &args.prompt), pamh, &user_name);
+                return Self::fail_unknown_user(
+                    pamh,
+                    flags,
+                    &args,
+                    catalog,
+                    &prompt,
+                    users_data,
+                    database.pepper.as_ref(),
+                );
+            }
+            None => return Err(Error::UnknownUser).attach(PamError::USER_UNKNOWN),
+        };
+
+        if user.is_disabled() {
+            return Err(Error::Disabled).attach(PamError::AUTH_ERR);
+        }
+        if account::is_expired(user, pin_data::unix_now()) {
+            return Err(Error::Expired).attach(PamError::AUTH_ERR);
+        }
+
+        let mut lockout = Self::load_lockout(&args, &user_name)?;
+        if let (Some(state_dir), Some(max_password_age)) = (&args.state_dir, args.max_password_age)
+        {
+            password_login::ensure_recent(
+                &StateStore::new(state_dir),
+                &user_name,
+                max_password_age,
+            )?;
+        }
+
+        let mut verified_pin = None;
+        let prompt = prompt::render(translate(catalog, &args.prompt), pamh, &user_name);
+        Self::authenticate_pin(pamh, flags, &args, catalog, &prompt, |pin| {
+            let is_duress = Self::verify_counted(
+                &args,
+                &mut lockout,
+                database.pepper.as_ref(),
+                user.pin_hash(),
+                user.duress_hash(),
+                pin,
+            )?;
+            verified_pin = Some((pin.to_owned(), is_duress));
+            Ok(())
+        })?;
+
+        let Some((pin, is_duress)) = verified_pin else {
+            return Ok(());
+        };
+        // The user is authenticated already, so failures from here on mustn't change that
+        let print_debug = |report: Report<Error>| {
+            if args.is_debug {
+                pam_utils::print_error(&report, pamh, flags, args.is_debug, catalog);
+            }
+        };
+        if let Some(previous_use) = lockout.as_ref().and_then(lockout::Lockout::previous_use) {
+            if let Err(report) = Self::show_previous_use(pamh, flags, catalog, previous_use) {
+                print_debug(report);
+            }
+        }
+        // Same as for the pin, so the duress pin can't be told apart
+        if let Err(report) = Self::record_auth_method(pamh, &args) {
+            print_debug(report);
+        }
+        if is_duress {
+            if let Err(report) = Self::raise_duress_alert(pamh, &args, &user_name) {
+                print_debug(report);
+            }
+            // The duress pin mustn't replace the pin
+            return Ok(());
+        }
+        let pepper = database.pepper.as_ref();
+        if let Err(report) = Self::unseal_password(pamh, &args, user, &pin, pepper) {
+            print_debug(report);
+        }
+        if let Some(rehash_target) = &args.rehash {
+            if Self::is_hash_outdated(rehash_target, pepper, user)
+                || Self::is_seal_outdated(pepper, user)
+            {
+                if let Err(report) =
+                    Self::rehash(&database, rehash_target, &user_name, user.pin_hash(), &pin)
+                {
+                    print_debug(report);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn show_previous_use(
+        pamh: &Pam,
+        flags: PamFlags,
+        catalog: Option<&Catalog>,
+        (last_used, failed_attempts): (u64, u32),
+    ) -> Result<()> {
+        if flags.contains(PamFlags::SILENT) {
+            return Ok(());
+        }
+        let message = translate(catalog, "Last pin login: {} / {} failed attempts since")
+            .replacen("{}", &pin_data::format_unix_time(last_used), 1)
+            .replacen("{}", &failed_attempts.to_string(), 1);
+        pamh.conv(Some(&message), pamsm::PamMsgStyle::TEXT_INFO)
+            .map_err(|pam_code| Report::new(Error::Pam).attach(pam_code))?;
+        Ok(())
+    }
+
+    /// Tells the following modules and the session, that a pin was used,
+    /// e.g. to require the password for sensitive actions.
+    fn record_auth_method(pamh: &Pam, args: &args::Args) -> Result<()> {
+        pam_utils::set_data_string(pamh, AUTH_METHOD_DATA, c"pin")
+            .map_err(|pam_code| Report::new(Error::Pam).attach(pam_code))?;
+        if let Some(auth_method_env) = &args.auth_method_env {
+            pamh.putenv(&format!("{}=pin", auth_method_env))
+                .map_err(|pam_code| Report::new(Error::Pam).attach(pam_code))?;
+        }
+        Ok(())
+    }
+
+    fn raise_duress_alert(pamh: &Pam, args: &args::Args, user_name: &str) -> Result<()> {
+        if let Some(duress_env) = &args.duress_env {
+            pamh.putenv(&format!("{}=1", duress_env))
+                .map_err(|pam_code| Report::new(Error::Pam).attach(pam_code))?;
+        }
+        if let Some(duress_alert) = &args.duress_alert {
+            duress::alert(duress_alert, user_name, Self::origin(pamh)?)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the pin with the real password for the following modules, e.g. to unlock a keyring.
+    fn unseal_password(
+        pamh: &Pam,
+        args: &args::Args,
+        user: &pin_data::User,
+        pin: &CStr,
+        pepper: Option<&pin_data::Pepper>,
+    ) -> Result<()> {
+        let Some(sealed_password) = user.sealed_password() else {
+            return Ok(());
+        };
+        args.cost_limits.check(&sealed_password.key())?;
+        let password = sealed_password
+            .unseal(pin.to_bytes(), pepper)
+            .change_context(Error::UnsealPassword)?;
+        let password = CString::new(password).change_context(Error::UnsealPassword)?;
+        Self::store_pin(pamh, &password)
+    }
+
+    fn is_hash_outdated(
+        rehash_target: &rehash::RehashTarget,
+        pepper: Option<&pin_data::Pepper>,
+        user: &pin_data::User,
+    ) -> bool {
+        rehash_target.is_outdated(&user.pin_hash())
+            || pepper.is_some_and(|pepper| pepper.is_outdated(&user.pin_hash()))
+    }
+
+    /// The sealed password has to follow the pepper rotation as well.
+    fn is_seal_outdated(pepper: Option<&pin_data::Pepper>, user: &pin_data::User) -> bool {
+        pepper
+            .zip(user.sealed_password())
+            .is_some_and(|(pepper, sealed_password)| pepper.is_outdated(&sealed_password.key()))
+    }
+
+    fn rehash(
+        database: &database::Databases,
+        rehash_target: &rehash::RehashTarget,
+        user_name: &str,
+        verified_hash: PasswordHash<'_>,
+        pin: &CStr,
+    ) -> Result<()> {
+        let pepper = database.pepper.as_ref();
+        database.update(user_name, |user| {
+            // Don't overwrite a pin, which was changed in the meantime
+            if user.pin_hash() != verified_hash {
+                return Ok(false);
+            }
+
+            if Self::is_hash_outdated(rehash_target, pepper, user) {
+                let new_hash = pin_data::hash_pin(
+                    pin.to_bytes(),
+                    rehash_target.algorithm,
+                    rehash_target.argon2_params.clone(),
+                    pepper,
+                )
+                .change_context(Error::HashPassword)?;
+                user.set_pin_hash(new_hash);
+            }
+            if Self::is_seal_outdated(pepper, user) {
+                let sealed_password = user
+                    .sealed_password()
+                    .map(|sealed_password| Self::reseal_password(sealed_password, pin, pin, pepper))
+                    .transpose()?;
+                user.set_sealed_password(sealed_password);
+            }
+            Ok(true)
+        })
+    }
+
+    /// Gets the pin from a previous module or the user and verifies it.
+    /// The user is prompted up to `retry` times.
+    fn authenticate_pin(
+        pamh: &Pam,
+        flags: PamFlags,
+        args: &args::Args,
+        catalog: Option<&Catalog>,
+        prompt: &str,
+        mut verify: impl FnMut(&CStr) -> Result<()>,
+    ) -> Result<()> {
+        let cached_pin = Self::get_cached_pin(args.first_pass, || {
+            Ok(pamh.get_cached_authtok()?.map(CStr::to_owned))
+        })?;
+        if let Some(verification) =
+            Self::verify_first_pass(args.first_pass, cached_pin, &mut verify)
+        {
+            if verification.as_ref().is_err_and(is_wrong_pin) {
+                Self::fail_delay(pamh, args, true);
+            }
+            return verification;
+        }
+
+        for attempt in 1..=args.retry.get() {
+            let pin = Self::get_user_pin(pamh, prompt)?;
+            Self::store_pin(pamh, &pin)?;
+
+            let Err(report) = verify(&pin) else {
+                return Ok(());
+            };
+            if !is_wrong_pin(&report) {
+                return Err(report);
+            }
+            let is_last_attempt = attempt == args.retry.get();
+            Self::fail_delay(pamh, args, is_last_attempt);
+
+            if is_last_attempt {
+                if attempt > 1 {
+                    return Err(report).attach(PamError::MAXTRIES);
+                }
+                return Err(report);
+            }
+            if !flags.contains(PamFlags::SILENT) {
+                let message = translate(catalog, "Wrong pin, try again");
+                pamh.conv(Some(message), pamsm::PamMsgStyle::ERROR_MSG)
+                    .map_err(|pam_code| Report::new(Error::Pam).attach(pam_code))?;
+            }
+        }
+        unreachable!("`retry` is at least 1")
+    }
+
+    /// Slows down guessing.
+    /// The last failure is delayed by libpam, so it isn't added to the delays of other modules.
+    /// Before a retry, the module waits itself.
+    fn fail_delay(pamh: &Pam, args: &args::Args, is_last_attempt: bool) {
+        if args.fail_delay == 0 {
+            return;
+        }
+        let delay = std::time::Duration::from_millis(args.fail_delay);
+        if is_last_attempt {
+            // Only fails without a handle
+            let _ = pam_utils::fail_delay(pamh, delay);
+        } else {
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// The kill switch for incidents.
+    /// Checked before the sandbox and any prompt.
+    fn check_disable_file(args: &args::Args) -> Result<()> {
+        let Some(disable_file) = args.disable_file.as_ref().filter(|path| path.exists()) else {
+            return Ok(());
+        };
+
+        pam_utils::log_notice(&format!(
+            "pam-pin: Pins are disabled, as {} exists",
+            disable_file.display()
+        ));
+        Err(Report::new(Error::DisabledByFile))
+            .attach_printable(format!("disable file: {}", disable_file.display()))
+            .attach(PamError::IGNORE)
+    }
+
+    /// Fails like a wrong pin would, so it can't be told which users have a pin.
+    fn fail_unknown_user(
+        pamh: &Pam,
+        flags: PamFlags,
+        args: &args::Args,
+        catalog: Option<&Catalog>,
+        prompt: &str,
+        users_data: &pin_data::Data,
+        pepper: Option<&pin_data::Pepper>,
+    ) -> Result<()> {
+        let dummy_hash = dummy::typical_hash(users_data);
+
+        Self::authenticate_pin(pamh, flags, args, catalog, prompt, |pin| {
+            let dummy_hash = dummy_hash.password_hash();
+            let is_possible = args.pin_policy.check(pin.to_bytes()).is_ok();
+            if is_possible && args.cost_limits.check(&dummy_hash).is_ok() {
+                let _ = Self::verify_pin(dummy_hash, pin.to_bytes(), pepper);
+            }
+            Err(Report::new(Error::UnknownUser)).change_context(Error::VerifyPassword)
+        })
+    }
+
+    fn change_pin(
+        pamh: &Pam,
+        flags: PamFlags,
+        args: Vec<String>,
+        catalog: Option<&Catalog>,
+    ) -> Result<()> {
+        let args = args::Args::try_from(args).attach(PamError::IGNORE)?;
+        // The first phase only checks if the pin could be changed
+        let is_update = flags.bits() & PAM_UPDATE_AUTHTOK != 0;
+
+        let user_name = pam_utils::get_username(pamh, Error::Pam, Error::UnknownUser)?;
+        let database = Self::open_database(&args, &user_name, is_update)?;
+        let loaded_database = database
+            .load(&user_name)?
+            .ok_or(Error::MissingDatabase)
+            .attach(PamError::IGNORE)?;
+        let user = loaded_database
+            .data
+            .get_by_name(&user_name)
+            .ok_or(Error::UnknownUser)
+            .attach(PamError::IGNORE)?;
+        Self::check_restrictions(pamh, &args, Some(user))?;
+
+        if !is_update {
+            return Ok(());
+        }
+
+        let mut lockout = Self::load_lockout(&args, &user_name)?;
+
+        let cached_old_pin = Self::get_cached_pin(args.first_pass, || {
+            Ok(pamh.get_cached_oldauthtok()?.map(CStr::to_owned))
+        })?;
+        let mut old_pin = None;
+        let pepper = database.pepper.as_ref();
+        let verification = Self::verify_first_pass(args.first_pass, cached_old_pin, |pin| {
+            Self::verify_counted(&args, &mut lockout, pepper, user.pin_hash(), None, pin)?;
+            old_pin = Some(pin.to_owned());
+            Ok(())
+        });
+        let old_pin = match verification {
+            Some(verification) => {
+                verification?;
+                old_pin.ok_or(Error::ReadPassword)?
+            }
+            None => {
+                let old_pin = Self::get_user_pin(pamh, translate(catalog, "Current pin: "))?;
+                Self::verify_counted(&args, &mut lockout, pepper, user.pin_hash(), None, &old_pin)?;
+                old_pin
+            }
+        };
+
+        let new_pin = if args.use_authtok {
+            // The new pin was already asked for by a previous module
+            pamh.get_cached_authtok()
+                .map_err(|pam_code| Report::new(Error::Pam).attach(pam_code))?
+                .map(CStr::to_owned)
+                .ok_or(Error::ReadPassword)
+                .attach(PamError::AUTHTOK_RECOVERY_ERR)?
+        } else {
+            let new_pin = Self::get_user_pin(pamh, translate(catalog, "New pin: "))?;
+            let retyped_pin = Self::get_user_pin(pamh, translate(catalog, "Retype new pin: "))?;
+            if new_pin != retyped_pin {
+                return Err(Error::PinMismatch).attach(PamError::AUTHTOK_ERR);
+            }
+            Self::store_pin(pamh, &new_pin)?;
+            new_pin
+        };
+        args.pin_policy
+            .check(new_pin.to_bytes())
+            .change_context(Error::PinPolicy)
+            .attach(PamError::AUTHTOK_ERR)?;
+        if args.remember > 0 {
+            // Older pins may have parameters below the current minimums
+            for hash in user.history() {
+                args.cost_limits
+                    .check_max(&hash)
+                    .attach(PamError::AUTHTOK_ERR)?;
+            }
+            if user.is_reused(new_pin.to_bytes(), pepper) {
+                return Err(Error::PinReused).attach(PamError::AUTHTOK_ERR);
+            }
+        }
+        // Otherwise every login would raise the duress alert
+        if let Some(duress_hash) = user.duress_hash() {
+            args.cost_limits
+                .check(&duress_hash)
+                .attach(PamError::AUTHTOK_ERR)?;
+            if Self::verify_pin(duress_hash, new_pin.to_bytes(), pepper).is_ok() {
+                return Err(Error::SameDuressPin).attach(PamError::AUTHTOK_ERR);
+            }
+        }
+
+        // Keep the algorithm and cost the administrator has chosen with `pin-gen`
+        let old_hash = user.pin_hash();
+        let algorithm = old_hash
+            .algorithm
+            .as_str()
+            .parse::<pin_data::HashAlgorithm>()
+            .change_context(Error::HashPassword)
+            .attach(PamError::AUTHTOK_ERR)?;
+        // Other algorithms use their recommended parameters
+        let argon2_params = match algorithm.argon2_algorithm() {
+            Some(_) => argon2::Params::try_from(&old_hash)
+                .change_context(Error::HashPassword)
+                .attach(PamError::AUTHTOK_ERR)?,
+            None => argon2::Params::default(),
+        };
+        let new_hash = pin_data::hash_pin(new_pin.to_bytes(), algorithm, argon2_params, pepper)
+            .change_context(Error::HashPassword)
+            .attach(PamError::AUTHTOK_ERR)?;
+        let sealed_password = user
+            .sealed_password()
+            .map(|sealed_password| {
+                Self::reseal_password(sealed_password, &old_pin, &new_pin, pepper)
+            })
+            .transpose()
+            .attach(PamError::AUTHTOK_ERR)?;
+
+        let verified_hash = user.pin_hash();
+
+        database
+            .update(&user_name, |user| {
+                // The old pin only proves the user knows the hash it was verified against
+                if user.pin_hash() != verified_hash {
+                    return Err(Report::new(Error::WriteDatabase))
+                        .attach_printable("The pin was changed in the meantime");
+                }
+                user.replace_pin_hash(new_hash, args.remember);
+                user.set_created_at(Some(pin_data::unix_now()));
+                user.set_sealed_password(sealed_password);
+                Ok(true)
+            })
+            .attach(PamError::AUTHTOK_ERR)
+    }
+
+    /// Keeps the sealed password usable with the new pin or pepper key.
+    fn reseal_password(
+        sealed_password: &pin_data::SealedPassword,
+        old_pin: &CStr,
+        new_pin: &CStr,
+        pepper: Option<&pin_data::Pepper>,
+    ) -> Result<pin_data::SealedPassword> {
+        let password = sealed_password
+            .unseal(old_pin.to_bytes(), pepper)
+            .change_context(Error::UnsealPassword)?;
+        let argon2_params = argon2::Params::try_from(&sealed_password.key()).unwrap_or_default();
+        pin_data::SealedPassword::seal(&password, new_pin.to_bytes(), argon2_params, pepper)
+            .change_context(Error::SealPassword)
+    }
+
+    fn check_account(
+        pamh: &Pam,
+        flags: PamFlags,
+        args: Vec<String>,
+        catalog: Option<&Catalog>,
+    ) -> Result<()> {
+        let args = args::Args::try_from(args).attach(PamError::IGNORE)?;
+        // Logins with e.g. the password are none of this module's business
+        pam_utils::get_data_string(pamh, AUTH_METHOD_DATA)
+            .map_err(|pam_code| Report::new(Error::Pam).attach(pam_code))?
+            .ok_or(Error::NotPinLogin)
+            .attach(PamError::IGNORE)?;
 
         let user_name = pam_utils::get_username(pamh, Error::Pam, Error::UnknownUser)?;
-        let users_data = pin_data::Data::from_file(&args.database_filepath)
-            .change_context(Error::LoadDatabase)?;
+        let database = Self::open_database(&args, &user_name, false)?;
+        let loaded_database = database
+            .load(&user_name)?
+            .ok_or(Error::MissingDatabase)
+            .attach(PamError::IGNORE)?;
+        let users_data = &loaded_database.data;
+        // Users without a pin are none of this module's business
         let user = users_data
             .get_by_name(&user_name)
             .ok_or(Error::UnknownUser)
-            .attach(PamError::USER_UNKNOWN)?;
+            .attach(PamError::IGNORE)?;
 
-        let pin = Self::get_user_pin(pamh)?;
+        if user.is_disabled() {
+            return Err(Error::Disabled).attach(PamError::ACCT_EXPIRED);
+        }
 
-        Self::verify_pin(user.pin_hash(), pin.to_bytes())?;
-        Ok(())
+        match account::expiry(user, args.max_age, args.warn_age, pin_data::unix_now()) {
+            account::Expiry::Valid => Ok(()),
+            account::Expiry::ExpiresSoon(days) => {
+                if !flags.contains(PamFlags::SILENT) {
+                    let warning = translate(catalog, "Your pin will expire in {} day(s)")
+                        .replace("{}", &days.to_string());
+                    pamh.conv(Some(&warning), pamsm::PamMsgStyle::TEXT_INFO)
+                        .map_err(|pam_code| Report::new(Error::Pam).attach(pam_code))?;
+                }
+                Ok(())
+            }
+            account::Expiry::ChangeRequired => {
+                Err(Error::ChangeRequired).attach(PamError::NEW_AUTHTOK_REQD)
+            }
+            account::Expiry::Expired => Err(Error::Expired).attach(PamError::ACCT_EXPIRED),
+        }
+    }
+}
+
+/// The key of the PAM data with the authentication method, a C string.
+const AUTH_METHOD_DATA: &CStr = c"pam_pin_auth_method";
+
+/// Users, who don't use a pin, aren't bothered with the ignored module.
+/// Other ignored errors, e.g. invalid arguments, are still shown.
+fn is_expected(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::UnknownUser | Error::MissingDatabase | Error::ExcludedByGroup | Error::NotPinLogin
+    )
+}
+
+/// Only a wrong pin may be retried, not e.g. a lockout.
+fn is_wrong_pin(report: &Report<Error>) -> bool {
+    matches!(report.current_context(), Error::VerifyPassword)
+}
+
+impl PamPin {
+    /// `failure` is the return code for `fail` in the control args.
+    fn call_handler<F>(
+        handler: F,
+        pamh: Pam,
+        flags: PamFlags,
+        args: Vec<String>,
+        failure: PamError,
+    ) -> PamError
+    where
+        F: Fn(&Pam, PamFlags, Vec<String>, Option<&Catalog>) -> Result<()> + Send,
+    {
+        // Invalid values are reported by the handler
+        let return_codes = args::ReturnCodes::try_from(&args[..]).unwrap_or_default();
+
+        pam_utils::do_call_handler_with(
+            handler,
+            pamh,
+            flags,
+            args,
+            Error::SandboxPanic,
+            |error| return_codes.return_code(error, failure),
+            is_expected,
+        )
     }
 }
 
 impl PamServiceModule for PamPin {
     fn authenticate(pamh: Pam, flags: PamFlags, args: Vec<String>) -> PamError {
-        pam_utils::do_call_handler(Self::auth, pamh, flags, args, Error::SandboxPanic)
+        Self::call_handler(Self::auth, pamh, flags, args, PamError::AUTH_ERR)
+    }
+
+    /// The module has no credentials, but `PAM_IGNORE` from all modules
+    /// would fail the `setcred` stack.
+    fn setcred(_pamh: Pam, _flags: PamFlags, _args: Vec<String>) -> PamError {
+        PamError::SUCCESS
+    }
+
+    fn acct_mgmt(pamh: Pam, flags: PamFlags, args: Vec<String>) -> PamError {
+        Self::call_handler(
+            Self::check_account,
+            pamh,
+            flags,
+            args,
+            PamError::PERM_DENIED,
+        )
+    }
+
+    fn chauthtok(pamh: Pam, flags: PamFlags, args: Vec<String>) -> PamError {
+        Self::call_handler(Self::change_pin, pamh, flags, args, PamError::AUTHTOK_ERR)
     }
 }
 
@@ -104,7 +943,7 @@ mod test {
         let hash = "$argon2d$v=19$m=4096,t=3,p=1$PFRID+hbQKjEFESZWQZMEA$mMpICfZn5N0bV13RJ3nWYfYXesgTJcPl81xwrqzDDLY";
         let hash = PasswordHash::new(hash).unwrap();
 
-        PamPin::verify_pin(hash, pin.as_bytes()).unwrap();
+        PamPin::verify_pin(hash, pin.as_bytes(), None).unwrap();
     }
 
     #[test]
@@ -119,6 +958,196 @@ mod test {
         let hash = "$argon2d$v=19$m=4096,t=3,p=1$PFRID+hbQKjEFESZWQZMEA$mMpICfZn5N0bV13RJ3nWYfYXesgTJcPl81xwrqzDDLY";
         let hash = PasswordHash::new(hash).unwrap();
 
-        let _ = PamPin::verify_pin(hash, pin.as_bytes()).unwrap_err();
+        let _ = PamPin::verify_pin(hash, pin.as_bytes(), None).unwrap_err();
+    }
+
+    #[test]
+    fn fall_back_to_prompt_only_when_trying_first_pass() {
+        let pin = CString::new("pw").unwrap();
+        let reject = |_: &CStr| Err(Report::new(Error::VerifyPassword));
+
+        assert!(
+            PamPin::verify_first_pass(args::FirstPass::Try, Some(pin.clone()), reject).is_none()
+        );
+        assert!(
+            PamPin::verify_first_pass(args::FirstPass::Use, Some(pin), reject)
+                .unwrap()
+                .is_err()
+        );
+        assert!(
+            PamPin::verify_first_pass(args::FirstPass::Use, None, |_| Ok(()))
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn dummy_hash_has_typical_parameters() {
+        let data: pin_data::Data = toml::from_str(
+            r#"
+            [[users]]
+            name = "a"
+            pin_hash = "$argon2d$v=19$m=4096,t=3,p=1$PFRID+hbQKjEFESZWQZMEA$mMpICfZn5N0bV13RJ3nWYfYXesgTJcPl81xwrqzDDLY"
+            [[users]]
+            name = "b"
+            pin_hash = "$argon2d$v=19$m=8,t=1,p=1$PFRID+hbQKjEFESZWQZMEA$mMpICfZn5N0bV13RJ3nWYfYXesgTJcPl81xwrqzDDLY"
+            [[users]]
+            name = "c"
+            pin_hash = "$argon2d$v=19$m=4096,t=3,p=1$IsboQeAUwe6xgPtZ+WoYwA$lPwhmhsOZymdymjMAtEizmQ8MKXG0MK0aL4+9rkoyac"
+            "#,
+        )
+        .unwrap();
+
+        let dummy_hash = dummy::typical_hash(&data);
+        let dummy_hash = dummy_hash.password_hash();
+        assert_eq!(dummy_hash.params.to_string(), "m=4096,t=3,p=1");
+        let _ = PamPin::verify_pin(dummy_hash, b"pw", None).unwrap_err();
+    }
+
+    #[test]
+    fn dummy_hash_without_users() {
+        let data: pin_data::Data = toml::from_str("users = []").unwrap();
+
+        let dummy_hash = dummy::typical_hash(&data);
+        let dummy_hash = dummy_hash.password_hash();
+        assert_eq!(dummy_hash.params.to_string(), "m=19456,t=2,p=1");
+        let _ = PamPin::verify_pin(dummy_hash, b"pw", None).unwrap_err();
+    }
+
+    #[test]
+    fn verify_all_algorithms() {
+        let algorithms = [
+            pin_data::HashAlgorithm::Argon2d,
+            pin_data::HashAlgorithm::Argon2i,
+            pin_data::HashAlgorithm::Argon2id,
+            #[cfg(feature = "scrypt")]
+            pin_data::HashAlgorithm::Scrypt,
+            #[cfg(feature = "pbkdf2")]
+            pin_data::HashAlgorithm::Pbkdf2Sha256,
+        ];
+
+        for algorithm in algorithms {
+            let params = argon2::Params::new(8, 1, 1, None).unwrap();
+            let hash = pin_data::hash_pin(b"pw", algorithm, params, None).unwrap();
+
+            PamPin::verify_pin(hash.password_hash(), b"pw", None).unwrap();
+            let _ = PamPin::verify_pin(hash.password_hash(), b"Pw", None).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn retry_only_wrong_pins() {
+        let hash = "$argon2d$v=19$m=4096,t=3,p=1$PFRID+hbQKjEFESZWQZMEA$mMpICfZn5N0bV13RJ3nWYfYXesgTJcPl81xwrqzDDLY";
+        let wrong_pin =
+            PamPin::verify_pin(PasswordHash::new(hash).unwrap(), b"Pw", None).unwrap_err();
+        assert!(is_wrong_pin(&wrong_pin));
+
+        let unknown_user = Report::new(Error::UnknownUser).change_context(Error::VerifyPassword);
+        assert!(is_wrong_pin(&unknown_user));
+
+        assert!(!is_wrong_pin(&Report::new(Error::Locked)));
+        assert!(!is_wrong_pin(&Report::new(Error::CostTooHigh)));
+    }
+
+    #[test]
+    fn override_return_codes() {
+        let args = |args: &[&str]| args.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let return_codes = args::ReturnCodes::try_from(
+            &args(&["unknown_user=ignore", "missing_db=fail", "disabled=fail"])[..],
+        )
+        .unwrap();
+
+        assert_eq!(
+            return_codes.return_code(&Error::UnknownUser, PamError::AUTH_ERR),
+            Some(PamError::IGNORE)
+        );
+        assert_eq!(
+            return_codes.return_code(&Error::MissingDatabase, PamError::PERM_DENIED),
+            Some(PamError::PERM_DENIED)
+        );
+        assert_eq!(
+            return_codes.return_code(&Error::DisabledByFile, PamError::AUTH_ERR),
+            Some(PamError::AUTH_ERR)
+        );
+        assert_eq!(
+            return_codes.return_code(&Error::Locked, PamError::AUTH_ERR),
+            None
+        );
+
+        let nullok = args::ReturnCodes::try_from(&args(&["nullok"])[..]).unwrap();
+        assert_eq!(
+            nullok.return_code(&Error::MissingDatabase, PamError::AUTH_ERR),
+            Some(PamError::IGNORE)
+        );
+        assert_eq!(
+            nullok.return_code(&Error::UnknownUser, PamError::AUTH_ERR),
+            None
+        );
+
+        args::ReturnCodes::try_from(&args(&["unknown_user=maybe"])[..]).unwrap_err();
+    }
+
+    #[test]
+    fn reject_impossible_pin_as_wrong() {
+        let hash = "$argon2d$v=19$m=4096,t=3,p=1$PFRID+hbQKjEFESZWQZMEA$mMpICfZn5N0bV13RJ3nWYfYXesgTJcPl81xwrqzDDLY";
+        let mut args = args::Args::try_from(vec!["db=/nonexistent.toml".to_string()]).unwrap();
+        args.pin_policy.charset = pin_data::Charset::Digits;
+
+        let report = PamPin::verify_counted(
+            &args,
+            &mut None,
+            None,
+            PasswordHash::new(hash).unwrap(),
+            None,
+            c"Pw",
+        )
+        .unwrap_err();
+        assert!(is_wrong_pin(&report));
+        assert!(report.contains::<pin_data::PolicyError>());
+    }
+
+    #[test]
+    fn parse_env_names() {
+        let args = |arg: &str| args::Args::try_from(vec!["db=/pins.toml".into(), arg.into()]);
+
+        let parsed = args("auth_method_env=PAM_AUTH_METHOD").unwrap();
+        assert_eq!(parsed.auth_method_env.as_deref(), Some("PAM_AUTH_METHOD"));
+        args("auth_method_env=").err().unwrap();
+        args("duress_env=A=1").err().unwrap();
+    }
+
+    #[test]
+    fn reseal_password() {
+        let params = argon2::Params::new(8, 1, 1, None).unwrap();
+        let sealed_password =
+            pin_data::SealedPassword::seal(b"password", b"old", params, None).unwrap();
+        assert!(sealed_password.key().hash.is_none());
+        sealed_password.unseal(b"new", None).unwrap_err();
+
+        let resealed_password =
+            PamPin::reseal_password(&sealed_password, c"old", c"new", None).unwrap();
+        assert_eq!(resealed_password.unseal(b"new", None).unwrap(), b"password");
+        let _ = PamPin::reseal_password(&sealed_password, c"wrong", c"new", None).unwrap_err();
+    }
+
+    #[test]
+    fn verify_duress_pin() {
+        let params = argon2::Params::new(8, 1, 1, None).unwrap();
+        let algorithm = pin_data::HashAlgorithm::default();
+        let hash = pin_data::hash_pin(b"pin", algorithm, params.clone(), None).unwrap();
+        let duress_hash = pin_data::hash_pin(b"duress", algorithm, params, None).unwrap();
+        let verify = |duress_hash: Option<&password_hash::PasswordHashString>, pin: &[u8]| {
+            PamPin::verify_pin_or_duress(
+                hash.password_hash(),
+                duress_hash.map(password_hash::PasswordHashString::password_hash),
+                pin,
+                None,
+            )
+        };
+
+        assert!(!verify(Some(&duress_hash), b"pin").unwrap());
+        assert!(verify(Some(&duress_hash), b"duress").unwrap());
+        let _ = verify(Some(&duress_hash), b"other").unwrap_err();
+        let _ = verify(None, b"duress").unwrap_err();
     }
 }
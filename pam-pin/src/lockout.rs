@@ -0,0 +1,160 @@
+use crate::args::LockoutArgs;
+use crate::{Error, Result};
+use error_stack::ResultExt;
+use pamsm::PamError;
+use pin_data::state::{StateStore, UserState};
+use pin_data::unix_now;
+
+/// Counts failed attempts of a user and locks the pin temporarily after too many.
+/// It records the last use as well, as it writes the state anyway.
+pub(crate) struct Lockout<'a> {
+    args: &'a LockoutArgs,
+    store: StateStore,
+    user_name: &'a str,
+    state: UserState,
+    /// The state before the latest success.
+    previous_state: Option<UserState>,
+}
+
+impl<'a> Lockout<'a> {
+    pub fn load(args: &'a LockoutArgs, store: StateStore, user_name: &'a str) -> Result<Self> {
+        let state = store.load(user_name).change_context(Error::LoadState)?;
+
+        Ok(Self {
+            args,
+            store,
+            user_name,
+            state,
+            previous_state: None,
+        })
+    }
+
+    /// Reloads the state, which stays unchanged by other processes until the lock is dropped.
+    pub fn lock(&mut self) -> Result<pin_data::FileLock> {
+        let lock = self
+            .store
+            .lock(self.user_name)
+            .change_context(Error::LoadState)?;
+        self.state = self
+            .store
+            .load(self.user_name)
+            .change_context(Error::LoadState)?;
+        Ok(lock)
+    }
+
+    pub fn ensure_unlocked(&self) -> Result<()> {
+        if is_locked(self.args, &self.state, unix_now()) {
+            return Err(Error::Locked).attach(PamError::MAXTRIES);
+        }
+        Ok(())
+    }
+
+    /// A success clears the counter.
+    pub fn record(&mut self, is_success: bool) -> Result<()> {
+        let now = unix_now();
+        if is_success {
+            self.previous_state = Some(self.state.clone());
+            self.state.failed_attempts = 0;
+            self.state.first_failure = None;
+            self.state.last_failure = None;
+            self.state.last_used = Some(now);
+            self.state.failed_since_last_use = 0;
+        } else {
+            register_failure(self.args, &mut self.state, now);
+            self.state.last_failed = Some(now);
+            self.state.failed_since_last_use = self.state.failed_since_last_use.saturating_add(1);
+        }
+
+        self.store
+            .store(self.user_name, &self.state)
+            .change_context(Error::StoreState)
+    }
+
+    /// Returns the last use before the current one and the failed attempts since.
+    pub fn previous_use(&self) -> Option<(u64, u32)> {
+        let previous_state = self.previous_state.as_ref()?;
+        previous_state
+            .last_used
+            .map(|last_used| (last_used, previous_state.failed_since_last_use))
+    }
+}
+
+fn is_locked(args: &LockoutArgs, state: &UserState, now: u64) -> bool {
+    if args.deny == 0 || state.failed_attempts < args.deny {
+        return false;
+    }
+    if args.unlock_time == 0 {
+        return true;
+    }
+    state
+        .last_failure
+        .is_some_and(|last_failure| now < last_failure.saturating_add(args.unlock_time))
+}
+
+fn register_failure(args: &LockoutArgs, state: &mut UserState, now: u64) {
+    let is_interval_over = state
+        .first_failure
+        .is_none_or(|first_failure| now >= first_failure.saturating_add(args.fail_interval));
+    // An expired lock starts a new interval as well
+    if is_interval_over || (state.failed_attempts >= args.deny && !is_locked(args, state, now)) {
+        state.failed_attempts = 0;
+        state.first_failure = Some(now);
+    }
+
+    state.failed_attempts = state.failed_attempts.saturating_add(1);
+    state.last_failure = Some(now);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lockout_args() -> LockoutArgs {
+        LockoutArgs {
+            deny: 3,
+            unlock_time: 600,
+            fail_interval: 900,
+        }
+    }
+
+    #[test]
+    fn lock_after_deny_failures() {
+        let args = lockout_args();
+        let mut state = UserState::default();
+
+        for now in [100, 200] {
+            register_failure(&args, &mut state, now);
+            assert!(!is_locked(&args, &state, now));
+        }
+        register_failure(&args, &mut state, 300);
+        assert!(is_locked(&args, &state, 300));
+        assert!(is_locked(&args, &state, 899));
+        assert!(!is_locked(&args, &state, 900));
+    }
+
+    #[test]
+    fn forget_failures_outside_interval() {
+        let args = lockout_args();
+        let mut state = UserState::default();
+
+        register_failure(&args, &mut state, 0);
+        register_failure(&args, &mut state, 100);
+        register_failure(&args, &mut state, 1000);
+        assert_eq!(state.failed_attempts, 1);
+        assert!(!is_locked(&args, &state, 1000));
+    }
+
+    #[test]
+    fn lock_permanently_without_unlock_time() {
+        let args = LockoutArgs {
+            unlock_time: 0,
+            ..lockout_args()
+        };
+        let mut state = UserState::default();
+
+        for now in [0, 1, 2] {
+            register_failure(&args, &mut state, now);
+        }
+        assert!(is_locked(&args, &state, u64::MAX));
+    }
+}
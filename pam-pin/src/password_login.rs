@@ -0,0 +1,50 @@
+use crate::{Error, Result};
+use error_stack::ResultExt;
+use pamsm::PamError;
+use pin_data::state::{StateStore, UserState};
+use pin_data::unix_now;
+
+const SECONDS_PER_HOUR: u64 = 60 * 60;
+
+/// Called after the password was verified by another module.
+pub(crate) fn mark(store: &StateStore, user_name: &str) -> Result<()> {
+    let _state_lock = store.lock(user_name).change_context(Error::LoadState)?;
+    let mut state = store.load(user_name).change_context(Error::LoadState)?;
+    state.last_password_login = Some(unix_now());
+    store
+        .store(user_name, &state)
+        .change_context(Error::StoreState)
+}
+
+/// `max_age` is in hours.
+pub(crate) fn ensure_recent(store: &StateStore, user_name: &str, max_age: u64) -> Result<()> {
+    let state = store.load(user_name).change_context(Error::LoadState)?;
+    if !is_recent(&state, max_age, unix_now()) {
+        return Err(Error::PasswordRequired).attach(PamError::AUTH_ERR);
+    }
+    Ok(())
+}
+
+fn is_recent(state: &UserState, max_age: u64, now: u64) -> bool {
+    state
+        .last_password_login
+        .is_some_and(|last_password_login| {
+            now < last_password_login.saturating_add(max_age.saturating_mul(SECONDS_PER_HOUR))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn password_login_expires() {
+        let mut state = UserState::default();
+        assert!(!is_recent(&state, 72, 0));
+
+        state.last_password_login = Some(100);
+        assert!(is_recent(&state, 72, 100));
+        assert!(is_recent(&state, 72, 100 + 72 * SECONDS_PER_HOUR - 1));
+        assert!(!is_recent(&state, 72, 100 + 72 * SECONDS_PER_HOUR));
+    }
+}
@@ -0,0 +1,63 @@
+use pamsm::{Pam, PamLibExt};
+
+pub(crate) const DEFAULT_PROMPT: &str = "Pin: ";
+
+/// Replaces `%u` (user), `%s` (service), `%h` (remote host), `%t` (tty) and `%%`.
+/// Unknown placeholders are kept.
+fn expand(template: &str, item: impl Fn(char) -> Option<String>) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => expanded.push('%'),
+            Some(placeholder @ ('u' | 's' | 'h' | 't')) => {
+                expanded.push_str(&item(placeholder).unwrap_or_default())
+            }
+            Some(other) => {
+                expanded.push('%');
+                expanded.push(other);
+            }
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
+
+pub(crate) fn render(template: &str, pamh: &Pam, user_name: &str) -> String {
+    expand(template, |placeholder| {
+        let item = match placeholder {
+            'u' => return Some(user_name.to_string()),
+            's' => pamh.get_service(),
+            'h' => pamh.get_rhost(),
+            't' => pam_utils::get_tty(pamh),
+            _ => return None,
+        };
+        item.ok()
+            .flatten()
+            .and_then(|item| item.to_str().ok())
+            .map(ToString::to_string)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expand_placeholders() {
+        let item = |placeholder| match placeholder {
+            'u' => Some("alice".to_string()),
+            's' => Some("sudo".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(expand("Pin for %u (%s): ", item), "Pin for alice (sudo): ");
+        assert_eq!(expand("%h%t", item), "");
+        assert_eq!(expand("100%% %x %", item), "100% %x %");
+    }
+}
@@ -0,0 +1,59 @@
+use argon2::password_hash::PasswordHash;
+use argon2::{Params, Version};
+use pin_data::HashAlgorithm;
+
+/// What new pins get hashed with.
+#[derive(Clone, Debug)]
+pub(crate) struct RehashTarget {
+    pub algorithm: HashAlgorithm,
+    pub argon2_params: Params,
+}
+
+impl RehashTarget {
+    pub fn is_outdated(&self, hash: &PasswordHash<'_>) -> bool {
+        if hash.algorithm.as_str() != self.algorithm.ident() {
+            return true;
+        }
+        if self.algorithm.argon2_algorithm().is_none() {
+            return false;
+        }
+
+        let Ok(params) = Params::try_from(hash) else {
+            return true;
+        };
+        hash.version != Some(Version::default().into())
+            || params.m_cost() != self.argon2_params.m_cost()
+            || params.t_cost() != self.argon2_params.t_cost()
+            || params.p_cost() != self.argon2_params.p_cost()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "$argon2d$v=19$m=4096,t=3,p=1$PFRID+hbQKjEFESZWQZMEA$mMpICfZn5N0bV13RJ3nWYfYXesgTJcPl81xwrqzDDLY";
+
+    fn target(m_cost: u32) -> RehashTarget {
+        RehashTarget {
+            algorithm: HashAlgorithm::Argon2d,
+            argon2_params: Params::new(m_cost, 3, 1, None).unwrap(),
+        }
+    }
+
+    #[test]
+    fn detect_outdated_parameters() {
+        let hash = PasswordHash::new(HASH).unwrap();
+
+        assert!(!target(4096).is_outdated(&hash));
+        assert!(target(8192).is_outdated(&hash));
+    }
+
+    #[test]
+    fn detect_outdated_algorithm() {
+        let hash = HASH.replace("argon2d", "argon2i");
+        let hash = PasswordHash::new(&hash).unwrap();
+
+        assert!(target(4096).is_outdated(&hash));
+    }
+}
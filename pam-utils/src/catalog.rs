@@ -0,0 +1,107 @@
+use pamsm::{Pam, PamLibExt};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Translations of messages, keyed by the english message.
+/// Messages without a translation are used as they are.
+#[derive(Default, Clone, Debug)]
+pub struct Catalog {
+    messages: HashMap<String, String>,
+}
+
+const LOCALE_VARIABLES: [&str; 3] = ["LC_ALL", "LC_MESSAGES", "LANG"];
+
+impl Catalog {
+    pub const CATALOG_DIR_ID: &'static str = "catalog=";
+
+    /// Loads `<dir>/<locale>.toml` for the locale of the session.
+    /// A missing or broken catalog isn't an error, as the untranslated messages are still usable.
+    pub fn for_session(dir: &Path, pamh: &Pam) -> Option<Self> {
+        let locale = session_locale(pamh)?;
+
+        locale_candidates(&locale)
+            .into_iter()
+            .find_map(|locale| Self::from_file(&dir.join(format!("{}.toml", locale))))
+    }
+
+    pub fn from_args(args: &[String], pamh: &Pam) -> Option<Self> {
+        let dir = crate::extract_named_value(args, Self::CATALOG_DIR_ID)?;
+        Self::for_session(Path::new(dir), pamh)
+    }
+
+    fn from_file(path: &Path) -> Option<Self> {
+        let messages = std::fs::read_to_string(path).ok()?;
+        let messages = toml::from_str(&messages).ok()?;
+        Some(Self { messages })
+    }
+
+    pub fn translate<'a>(&'a self, message: &'a str) -> &'a str {
+        self.messages
+            .get(message)
+            .map(String::as_str)
+            .unwrap_or(message)
+    }
+}
+
+/// Translates with an optional catalog.
+pub fn translate<'a>(catalog: Option<&'a Catalog>, message: &'a str) -> &'a str {
+    catalog.map_or(message, |catalog| catalog.translate(message))
+}
+
+/// The PAM environment takes precedence over the one of the process.
+fn session_locale(pamh: &Pam) -> Option<String> {
+    LOCALE_VARIABLES.iter().find_map(|variable| {
+        let pam_value = pamh
+            .getenv(variable)
+            .ok()
+            .flatten()
+            .and_then(|value| value.to_str().ok())
+            .map(ToString::to_string);
+        pam_value
+            .or_else(|| std::env::var(variable).ok())
+            .filter(|value| !value.is_empty())
+    })
+}
+
+/// `de_DE.UTF-8@euro` results in `de_DE` and `de`.
+fn locale_candidates(locale: &str) -> Vec<&str> {
+    let locale = locale.split(['.', '@']).next().unwrap_or_default();
+    // Also keeps the locale from being a path
+    let is_valid = !locale.is_empty()
+        && locale
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !is_valid || locale == "C" || locale == "POSIX" {
+        return Vec::new();
+    }
+
+    let mut candidates = vec![locale];
+    if let Some((language, _)) = locale.split_once('_') {
+        candidates.push(language);
+    }
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locale_fallbacks() {
+        assert_eq!(locale_candidates("de_DE.UTF-8@euro"), ["de_DE", "de"]);
+        assert_eq!(locale_candidates("fr"), ["fr"]);
+        assert!(locale_candidates("C.UTF-8").is_empty());
+        assert!(locale_candidates("../../etc/shadow").is_empty());
+    }
+
+    #[test]
+    fn untranslated_fallback() {
+        let catalog = Catalog {
+            messages: [("Pin: ".to_string(), "PIN: ".to_string())].into(),
+        };
+
+        assert_eq!(translate(Some(&catalog), "Pin: "), "PIN: ");
+        assert_eq!(translate(Some(&catalog), "Error"), "Error");
+        assert_eq!(translate(None, "Pin: "), "Pin: ");
+    }
+}
@@ -1,6 +1,14 @@
+mod catalog;
+mod libpam;
+mod passwd;
+
 use error_stack::ResultExt;
 use pamsm::{Pam, PamError, PamFlags, PamLibExt};
 
+pub use catalog::{translate, Catalog};
+pub use libpam::{fail_delay, get_data_string, get_tty, set_data_string};
+pub use passwd::{get_group_id, get_group_list, get_passwd, Passwd, PrivateFile};
+
 #[macro_export]
 macro_rules! err_try {
     ($res:expr) => {
@@ -19,7 +27,7 @@ compile_error!(
 
 pub fn do_call_handler<C, F>(
     handler: F,
-    mut pamh: Pam,
+    pamh: Pam,
     flags: PamFlags,
     args: Vec<String>,
     sandbox_panic_error: C,
@@ -27,22 +35,69 @@ pub fn do_call_handler<C, F>(
 where
     C: error_stack::Context,
     F: Fn(&Pam, PamFlags, Vec<String>) -> error_stack::Result<(), C> + Send,
+{
+    do_call_handler_with(
+        move |pamh, flags, args, _| handler(pamh, flags, args),
+        pamh,
+        flags,
+        args,
+        sandbox_panic_error,
+        |_| None,
+        |_| false,
+    )
+}
+
+/// The catalog of the errors is passed to the `handler` as well, so it is only loaded once.
+/// `return_code` can override the return code of an error by its context,
+/// e.g. to ignore unknown users as configured by the module arguments.
+/// `is_expected` tells the contexts, which aren't shown to the user when they are ignored,
+/// e.g. for users without a pin.
+pub fn do_call_handler_with<C, F, R, E>(
+    handler: F,
+    mut pamh: Pam,
+    flags: PamFlags,
+    args: Vec<String>,
+    sandbox_panic_error: C,
+    return_code: R,
+    is_expected: E,
+) -> PamError
+where
+    C: error_stack::Context,
+    F: Fn(&Pam, PamFlags, Vec<String>, Option<&Catalog>) -> error_stack::Result<(), C> + Send,
+    R: Fn(&C) -> Option<PamError>,
+    E: Fn(&C) -> bool,
 {
     let is_debug = is_debug(&args);
+    // Loaded outside of the sandboxed thread
+    let catalog = Catalog::from_args(&args, &pamh);
 
     #[cfg(not(feature = "sandbox"))]
-    let res = handler(&pamh, flags, args);
+    let res = handler(&pamh, flags, args, catalog.as_ref());
 
     #[cfg(feature = "sandbox")]
-    let res = do_threaded_call(&mut pamh, handler, flags, args, sandbox_panic_error);
+    let res = do_threaded_call(
+        &mut pamh,
+        handler,
+        flags,
+        args,
+        catalog.as_ref(),
+        sandbox_panic_error,
+    );
 
     if let Err(error_context) = res {
-        print_error(&error_context, &pamh, flags, is_debug);
+        let pam_error = return_code(error_context.current_context()).unwrap_or_else(|| {
+            error_context
+                .downcast_ref::<PamError>()
+                .map(Clone::clone)
+                .unwrap_or(PamError::AUTH_ERR)
+        });
 
-        return error_context
-            .downcast_ref::<PamError>()
-            .map(Clone::clone)
-            .unwrap_or(PamError::AUTH_ERR);
+        let is_quiet =
+            pam_error == PamError::IGNORE && is_expected(error_context.current_context());
+        if is_debug || !is_quiet {
+            print_error(&error_context, &pamh, flags, is_debug, catalog.as_ref());
+        }
+        return pam_error;
     }
     PamError::SUCCESS
 }
@@ -53,26 +108,28 @@ fn do_threaded_call<C, F>(
     handler: F,
     flags: PamFlags,
     args: Vec<String>,
+    catalog: Option<&Catalog>,
     sandbox_panic_error: C,
 ) -> error_stack::Result<(), C>
 where
     C: error_stack::Context,
-    F: Fn(&Pam, PamFlags, Vec<String>) -> error_stack::Result<(), C> + Send,
+    F: Fn(&Pam, PamFlags, Vec<String>, Option<&Catalog>) -> error_stack::Result<(), C> + Send,
 {
     std::thread::scope(|scope| {
         let moving_handle = pamh.as_send_ref();
-        let sandbox_thread = scope.spawn(move || handler(&moving_handle, flags, args));
+        let sandbox_thread = scope.spawn(move || handler(&moving_handle, flags, args, catalog));
         sandbox_thread
             .join()
             .map_err(|_| error_stack::Report::new(sandbox_panic_error))
     })?
 }
 
-fn print_error<C>(
+pub fn print_error<C>(
     error_context: &error_stack::Report<C>,
     pamh: &Pam,
     flags: PamFlags,
     is_debug: bool,
+    catalog: Option<&Catalog>,
 ) where
     C: error_stack::Context,
 {
@@ -80,7 +137,11 @@ fn print_error<C>(
         let error_message = if is_debug {
             format!("Error: {:?}", error_context)
         } else {
-            format!("Error: {}", error_context)
+            format!(
+                "{}: {}",
+                translate(catalog, "Error"),
+                translate(catalog, &error_context.to_string())
+            )
         };
         let print_error = "Couldn't print error message";
         let input = pamh
@@ -90,12 +151,45 @@ fn print_error<C>(
     }
 }
 
+/// Logs to the `authpriv` facility, e.g. for the admin instead of the user.
+/// Has to be called before the sandbox is active.
+pub fn log_notice(message: &str) {
+    let Ok(message) = std::ffi::CString::new(message) else {
+        return;
+    };
+    // SAFETY: The format string consumes exactly the one null terminated argument
+    unsafe {
+        libc::syslog(
+            libc::LOG_AUTHPRIV | libc::LOG_NOTICE,
+            c"%s".as_ptr(),
+            message.as_ptr(),
+        );
+    }
+}
+
 pub fn extract_named_value<'a>(args: &'a [String], key: &str) -> Option<&'a str> {
     args.iter()
         .find(|arg| arg.starts_with(key))
         .map(|value| value.trim_start_matches(key))
 }
 
+/// For keys, which can be given multiple times.
+pub fn extract_named_values<'a>(
+    args: &'a [String],
+    key: &'a str,
+) -> impl Iterator<Item = &'a str> + 'a {
+    args.iter()
+        .filter(move |arg| arg.starts_with(key))
+        .map(move |value| value.trim_start_matches(key))
+}
+
+pub fn parse_named_value<T: std::str::FromStr>(
+    args: &[String],
+    key: &str,
+) -> Option<Result<T, T::Err>> {
+    extract_named_value(args, key).map(str::parse)
+}
+
 const DEBUG_ID: &str = "debug";
 
 pub fn is_debug(args: &[String]) -> bool {
@@ -0,0 +1,102 @@
+//! The parts of libpam pamsm doesn't wrap.
+
+use pamsm::{Pam, PamError, PamResult};
+use std::ffi::{c_char, c_int, c_uint, c_void, CStr};
+
+/// `PAM_TTY` of `<security/_pam_types.h>`
+const PAM_TTY: c_int = 3;
+
+/// `PAM_NO_MODULE_DATA` of `<security/_pam_types.h>`
+const PAM_NO_MODULE_DATA: c_int = 18;
+
+type Cleanup = unsafe extern "C" fn(pamh: *const c_void, data: *mut c_void, error_status: c_int);
+
+extern "C" {
+    fn pam_get_item(pamh: *const c_void, item_type: c_int, item: *mut *const c_void) -> c_int;
+    fn pam_fail_delay(pamh: *const c_void, micro_sec: c_uint) -> c_int;
+    fn pam_set_data(
+        pamh: *const c_void,
+        module_data_name: *const c_char,
+        data: *mut c_void,
+        cleanup: Option<Cleanup>,
+    ) -> c_int;
+    fn pam_get_data(
+        pamh: *const c_void,
+        module_data_name: *const c_char,
+        data: *mut *const c_void,
+    ) -> c_int;
+}
+
+/// The raw handle, as pamsm doesn't give access to it.
+fn handle(pamh: &Pam) -> *const c_void {
+    // SAFETY: `Pam` is a `#[repr(transparent)]` wrapper of the handle pointer
+    unsafe { *(pamh as *const Pam).cast::<*const c_void>() }
+}
+
+/// pamsm can't construct errors from raw codes outside of its crate.
+fn to_result(code: c_int) -> PamResult<()> {
+    match code {
+        0 => Ok(()),
+        4 => Err(PamError::SYSTEM_ERR),
+        5 => Err(PamError::BUF_ERR),
+        6 => Err(PamError::PERM_DENIED),
+        18 => Err(PamError::NO_MODULE_DATA),
+        29 => Err(PamError::BAD_ITEM),
+        _ => Err(PamError::SERVICE_ERR),
+    }
+}
+
+/// The `PAM_TTY` item, like the item getters of [`pamsm::PamLibExt`].
+pub fn get_tty(pamh: &Pam) -> PamResult<Option<&CStr>> {
+    let mut item = std::ptr::null();
+    // SAFETY: The handle is valid for the lifetime of `pamh`
+    to_result(unsafe { pam_get_item(handle(pamh), PAM_TTY, &mut item) })?;
+    if item.is_null() {
+        return Ok(None);
+    }
+    // SAFETY: libpam keeps the string allocated while the module runs
+    Ok(Some(unsafe { CStr::from_ptr(item.cast::<c_char>()) }))
+}
+
+/// Asks libpam to delay a failed authentication, it uses the longest delay of all modules.
+pub fn fail_delay(pamh: &Pam, duration: std::time::Duration) -> PamResult<()> {
+    let micro_sec = duration.as_micros().try_into().unwrap_or(c_uint::MAX);
+    // SAFETY: The handle is valid for the lifetime of `pamh`
+    to_result(unsafe { pam_fail_delay(handle(pamh), micro_sec) })
+}
+
+unsafe extern "C" fn free_data(_pamh: *const c_void, data: *mut c_void, _error_status: c_int) {
+    libc::free(data);
+}
+
+/// Stores a plain C string as PAM data, so modules written in C can read it.
+/// [`pamsm::PamLibExt::send_bytes`] stores a Rust type only pamsm can read.
+pub fn set_data_string(pamh: &Pam, name: &CStr, value: &CStr) -> PamResult<()> {
+    // SAFETY: `value` is null terminated, libpam frees the copy with `free_data`
+    let data = unsafe { libc::strdup(value.as_ptr()) };
+    if data.is_null() {
+        return Err(PamError::BUF_ERR);
+    }
+    // SAFETY: The handle is valid for the lifetime of `pamh`
+    let result = to_result(unsafe {
+        pam_set_data(handle(pamh), name.as_ptr(), data.cast(), Some(free_data))
+    });
+    if result.is_err() {
+        // SAFETY: libpam didn't take the copy
+        unsafe { libc::free(data.cast()) };
+    }
+    result
+}
+
+/// Reads PAM data stored by [`set_data_string`] or by a C module, `None` if it isn't set.
+pub fn get_data_string<'a>(pamh: &'a Pam, name: &CStr) -> PamResult<Option<&'a CStr>> {
+    let mut data = std::ptr::null();
+    // SAFETY: The handle is valid for the lifetime of `pamh`
+    let code = unsafe { pam_get_data(handle(pamh), name.as_ptr(), &mut data) };
+    if code == PAM_NO_MODULE_DATA || (code == 0 && data.is_null()) {
+        return Ok(None);
+    }
+    to_result(code)?;
+    // SAFETY: The data is a C string until it's replaced or the handle ends
+    Ok(Some(unsafe { CStr::from_ptr(data.cast::<c_char>()) }))
+}
@@ -0,0 +1,275 @@
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+/// The parts of a user's passwd entry the modules need.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Passwd {
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+}
+
+/// Looks the user up through NSS.
+/// Has to be called before the sandbox is active, as NSS modules can need any file or socket.
+pub fn get_passwd(name: &str) -> std::io::Result<Option<Passwd>> {
+    let name = CString::new(name).map_err(std::io::Error::other)?;
+    let mut buffer = vec![0; 1024];
+
+    loop {
+        let mut passwd = std::mem::MaybeUninit::<libc::passwd>::uninit();
+        let mut result = std::ptr::null_mut();
+        // SAFETY: All pointers are valid for the given buffer length
+        let error = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                passwd.as_mut_ptr(),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        if error == libc::ERANGE {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+        if error != 0 {
+            return Err(std::io::Error::from_raw_os_error(error));
+        }
+        if result.is_null() {
+            return Ok(None);
+        }
+
+        // SAFETY: The entry is initialized, as `result` isn't null
+        let passwd = unsafe { passwd.assume_init() };
+        // SAFETY: `pw_dir` points into `buffer` and is null terminated
+        let home = unsafe { CStr::from_ptr(passwd.pw_dir) };
+        return Ok(Some(Passwd {
+            uid: passwd.pw_uid,
+            gid: passwd.pw_gid,
+            home: OsStr::from_bytes(home.to_bytes()).into(),
+        }));
+    }
+}
+
+/// Looks the group id up through NSS.
+/// Like [`get_passwd`], it has to be called before the sandbox is active.
+pub fn get_group_id(name: &str) -> std::io::Result<Option<u32>> {
+    let name = CString::new(name).map_err(std::io::Error::other)?;
+    let mut buffer = vec![0; 1024];
+
+    loop {
+        let mut group = std::mem::MaybeUninit::<libc::group>::uninit();
+        let mut result = std::ptr::null_mut();
+        // SAFETY: All pointers are valid for the given buffer length
+        let error = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                group.as_mut_ptr(),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        if error == libc::ERANGE {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+        if error != 0 {
+            return Err(std::io::Error::from_raw_os_error(error));
+        }
+        if result.is_null() {
+            return Ok(None);
+        }
+
+        // SAFETY: The entry is initialized, as `result` isn't null
+        return Ok(Some(unsafe { group.assume_init() }.gr_gid));
+    }
+}
+
+/// The ids of all groups of the user, including the primary group `gid`.
+/// Like [`get_passwd`], it has to be called before the sandbox is active.
+pub fn get_group_list(name: &str, gid: u32) -> std::io::Result<Vec<u32>> {
+    let name = CString::new(name).map_err(std::io::Error::other)?;
+    let mut groups = vec![0; 32];
+
+    loop {
+        let mut group_count = libc::c_int::try_from(groups.len()).map_err(std::io::Error::other)?;
+        // SAFETY: `groups` is valid for `group_count` entries
+        let result = unsafe {
+            libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut group_count)
+        };
+        let group_count = usize::try_from(group_count).map_err(std::io::Error::other)?;
+        if result == -1 {
+            // `group_count` is the required length now
+            groups.resize(group_count.max(groups.len() * 2), 0);
+            continue;
+        }
+
+        groups.truncate(group_count);
+        return Ok(groups);
+    }
+}
+
+/// A file of the user, e.g. in the home directory.
+/// Its directory is opened once without following symlinks,
+/// so the user can't redirect the access to a file of someone else by swapping a directory.
+#[derive(Debug)]
+pub struct PrivateFile {
+    dir: File,
+    name: OsString,
+    owner: Passwd,
+}
+
+impl PrivateFile {
+    /// Below the home, every directory has to be owned by the user or root and mustn't be writeable by others.
+    /// A path outside of the home is walked from `/`.
+    /// Has to be called before the sandbox is active, as it opens the directories.
+    pub fn new(path: &Path, owner: Passwd) -> std::io::Result<Self> {
+        let invalid_path = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid path");
+        let name = path.file_name().ok_or_else(invalid_path)?.to_owned();
+        let parent = path.parent().ok_or_else(invalid_path)?;
+        let (base, relative_path) = match parent.strip_prefix(&owner.home) {
+            Ok(relative_path) => (owner.home.as_path(), relative_path),
+            Err(_) => (
+                Path::new("/"),
+                parent.strip_prefix("/").map_err(|_| invalid_path())?,
+            ),
+        };
+
+        // The home itself is given by NSS, so it may be a symlink
+        let mut dir = open_at(None, base.as_os_str(), libc::O_DIRECTORY, 0)?;
+        check_dir(&dir, owner.uid)?;
+        for component in relative_path.components() {
+            let Component::Normal(component) = component else {
+                return Err(invalid_path());
+            };
+            dir = open_at(
+                Some(&dir),
+                component,
+                libc::O_DIRECTORY | libc::O_NOFOLLOW,
+                0,
+            )?;
+            check_dir(&dir, owner.uid)?;
+        }
+
+        Ok(Self { dir, name, owner })
+    }
+
+    /// Opens the file, which only its owner can access.
+    /// Symlinks are rejected.
+    pub fn open(&self) -> std::io::Result<File> {
+        let file = open_at(Some(&self.dir), &self.name, libc::O_NOFOLLOW, 0)?;
+        let metadata = file.metadata()?;
+
+        if !metadata.is_file() {
+            return Err(std::io::Error::other("Not a regular file"));
+        }
+        if metadata.uid() != self.owner.uid {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "Not owned by the user",
+            ));
+        }
+        if metadata.mode() & 0o077 != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "Accessible by other users",
+            ));
+        }
+
+        Ok(file)
+    }
+
+    /// Replaces the file by renaming a fully written sibling over it, which is given to the user.
+    pub fn replace(&self, data: &[u8]) -> std::io::Result<()> {
+        // Unique, so concurrent writers can't write to the same file
+        let mut temporary_name = self.name.clone();
+        temporary_name.push(format!(".{:016x}.tmp", random_u64()?));
+        let temporary_name = CString::new(temporary_name.as_bytes())?;
+        let name = CString::new(self.name.as_bytes())?;
+
+        let mut file = open_at(
+            Some(&self.dir),
+            OsStr::from_bytes(temporary_name.to_bytes()),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW,
+            0o600,
+        )?;
+        // Before the rename, so it can't be redirected to another file
+        std::os::unix::fs::fchown(&file, Some(self.owner.uid), Some(self.owner.gid))?;
+        file.write_all(data)?;
+        file.sync_all()?;
+
+        // SAFETY: The names are null terminated and the directory is open
+        let result = unsafe {
+            libc::renameat(
+                self.dir.as_raw_fd(),
+                temporary_name.as_ptr(),
+                self.dir.as_raw_fd(),
+                name.as_ptr(),
+            )
+        };
+        if result == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn random_u64() -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    // SAFETY: The buffer has the given length
+    let length = unsafe { libc::getrandom(bytes.as_mut_ptr().cast(), bytes.len(), 0) };
+    if length != 8 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(u64::from_ne_bytes(bytes))
+}
+
+/// `dir` is the base of a relative `path`, otherwise it's the working directory.
+/// Without `O_WRONLY` or `O_RDWR` in the `flags`, the file is opened read-only.
+fn open_at(
+    dir: Option<&File>,
+    path: &OsStr,
+    flags: libc::c_int,
+    mode: libc::mode_t,
+) -> std::io::Result<File> {
+    let path = CString::new(path.as_bytes())?;
+    let dir = dir.map_or(libc::AT_FDCWD, AsRawFd::as_raw_fd);
+    // SAFETY: The path is null terminated and the directory is open
+    let fd = unsafe {
+        libc::openat(
+            dir,
+            path.as_ptr(),
+            flags | libc::O_CLOEXEC,
+            libc::c_uint::from(mode),
+        )
+    };
+    if fd == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: The file descriptor was just opened and isn't owned by anything else
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Others could swap the entries of the directory.
+fn check_dir(dir: &File, owner: u32) -> std::io::Result<()> {
+    let metadata = dir.metadata()?;
+    if metadata.uid() != owner && metadata.uid() != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "A directory isn't owned by the user or root",
+        ));
+    }
+    if metadata.mode() & 0o002 != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "A directory is writeable by everyone",
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use crate::pepper::{self, Pepper};
+use argon2::password_hash::{
+    self, rand_core::OsRng, PasswordHash, PasswordHashString, PasswordHasher, PasswordVerifier,
+    SaltString,
+};
+use argon2::{Argon2, KeyId, Params, ParamsBuilder, Version};
+use std::fmt;
+use std::str::FromStr;
+
+/// The algorithms new pins can be hashed with.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[default]
+    Argon2d,
+    Argon2i,
+    Argon2id,
+    #[cfg(feature = "scrypt")]
+    Scrypt,
+    #[cfg(feature = "pbkdf2")]
+    Pbkdf2Sha256,
+}
+
+impl HashAlgorithm {
+    /// The identifier in the PHC string.
+    pub fn ident(&self) -> &'static str {
+        match self {
+            Self::Argon2d => "argon2d",
+            Self::Argon2i => "argon2i",
+            Self::Argon2id => "argon2id",
+            #[cfg(feature = "scrypt")]
+            Self::Scrypt => "scrypt",
+            #[cfg(feature = "pbkdf2")]
+            Self::Pbkdf2Sha256 => "pbkdf2-sha256",
+        }
+    }
+
+    pub fn argon2_algorithm(&self) -> Option<argon2::Algorithm> {
+        match self {
+            Self::Argon2d => Some(argon2::Algorithm::Argon2d),
+            Self::Argon2i => Some(argon2::Algorithm::Argon2i),
+            Self::Argon2id => Some(argon2::Algorithm::Argon2id),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = password_hash::Error;
+
+    fn from_str(ident: &str) -> Result<Self, Self::Err> {
+        match ident {
+            "argon2d" => Ok(Self::Argon2d),
+            "argon2i" => Ok(Self::Argon2i),
+            "argon2id" => Ok(Self::Argon2id),
+            #[cfg(feature = "scrypt")]
+            "scrypt" => Ok(Self::Scrypt),
+            #[cfg(feature = "pbkdf2")]
+            "pbkdf2-sha256" => Ok(Self::Pbkdf2Sha256),
+            _ => Err(password_hash::Error::Algorithm),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.ident())
+    }
+}
+
+/// The hashing used for all new pins.
+/// `argon2_params` are ignored by other algorithms, which use their recommended parameters.
+/// The `pepper` is only supported by Argon2.
+pub fn hash_pin(
+    pin: &[u8],
+    algorithm: HashAlgorithm,
+    argon2_params: Params,
+    pepper: Option<&Pepper>,
+) -> password_hash::Result<PasswordHashString> {
+    let salt = SaltString::generate(&mut OsRng);
+    if pepper.is_some() && algorithm.argon2_algorithm().is_none() {
+        return Err(password_hash::Error::Algorithm);
+    }
+
+    let hash = match algorithm {
+        HashAlgorithm::Argon2d | HashAlgorithm::Argon2i | HashAlgorithm::Argon2id => {
+            let argon2_algorithm = algorithm
+                .argon2_algorithm()
+                .ok_or(password_hash::Error::Algorithm)?;
+            match pepper.map(Pepper::current_key) {
+                Some((key_id, secret)) => Argon2::new_with_secret(
+                    secret,
+                    argon2_algorithm,
+                    Version::default(),
+                    with_key_id(&argon2_params, key_id)?,
+                )?
+                .hash_password(pin, &salt)?,
+                None => Argon2::new(argon2_algorithm, Version::default(), argon2_params)
+                    .hash_password(pin, &salt)?,
+            }
+        }
+        #[cfg(feature = "scrypt")]
+        HashAlgorithm::Scrypt => scrypt::Scrypt.hash_password(pin, &salt)?,
+        #[cfg(feature = "pbkdf2")]
+        HashAlgorithm::Pbkdf2Sha256 => pbkdf2::Pbkdf2.hash_password(pin, &salt)?,
+    };
+    Ok(hash.serialize())
+}
+
+/// The hash records the key id, so the pepper can be rotated.
+pub(crate) fn with_key_id(argon2_params: &Params, key_id: KeyId) -> password_hash::Result<Params> {
+    let mut params = ParamsBuilder::new();
+    params
+        .m_cost(argon2_params.m_cost())
+        .t_cost(argon2_params.t_cost())
+        .p_cost(argon2_params.p_cost())
+        .keyid(key_id);
+    if let Some(output_len) = argon2_params.output_len() {
+        params.output_len(output_len);
+    }
+    Ok(params.build()?)
+}
+
+/// The implementation for the identifier of a PHC string.
+/// Peppered hashes need the key of their id.
+fn verifier<'a>(
+    hash: &PasswordHash<'_>,
+    pepper: Option<&'a Pepper>,
+) -> Option<Box<dyn PasswordVerifier + 'a>> {
+    match hash.algorithm.as_str() {
+        "argon2d" | "argon2i" | "argon2id" => match pepper::key_id(hash) {
+            Some(key_id) => {
+                let secret = pepper?.secret(&key_id)?;
+                let argon2 = Argon2::new_with_secret(
+                    secret,
+                    Default::default(),
+                    Version::default(),
+                    Params::default(),
+                )
+                .ok()?;
+                Some(Box::new(argon2))
+            }
+            None => Some(Box::new(Argon2::default())),
+        },
+        #[cfg(feature = "scrypt")]
+        "scrypt" => Some(Box::new(scrypt::Scrypt)),
+        #[cfg(feature = "pbkdf2")]
+        "pbkdf2" | "pbkdf2-sha256" | "pbkdf2-sha512" => Some(Box::new(pbkdf2::Pbkdf2)),
+        _ => None,
+    }
+}
+
+/// See [`crate::has_pepper_key`] to tell a missing key from a wrong pin.
+pub fn verify_pin(
+    hash: &PasswordHash<'_>,
+    pin: &[u8],
+    pepper: Option<&Pepper>,
+) -> password_hash::Result<()> {
+    let verifier = verifier(hash, pepper).ok_or(password_hash::Error::Algorithm)?;
+
+    hash.verify_password(&[verifier.as_ref()], pin)
+}
@@ -1,3 +1,4 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
 use argon2::password_hash::{PasswordHash, PasswordHashString};
 use error_stack::ResultExt;
 use serde::{Deserialize, Deserializer, Serializer};
@@ -5,13 +6,112 @@ use serde_derive::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
+mod hash;
+mod pepper;
+mod policy;
+mod seal;
+pub mod state;
+
+pub use hash::{hash_pin, verify_pin, HashAlgorithm};
+pub use pepper::{has_pepper_key, Pepper};
+pub use policy::{Charset, PinPolicy, PolicyError};
+pub use seal::{SealError, SealedPassword};
+
+/// All timestamps are in seconds since the unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// The default kill switch of a database, which disables all pins while it exists.
+pub fn default_disable_file(database: &Path) -> PathBuf {
+    let mut disable_file = database.as_os_str().to_owned();
+    disable_file.push(".disabled");
+    disable_file.into()
+}
+
+/// As `YYYY-MM-DD hh:mm:ss UTC`.
+pub fn format_unix_time(timestamp: u64) -> String {
+    const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+    let (days, seconds) = (timestamp / SECONDS_PER_DAY, timestamp % SECONDS_PER_DAY);
+
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct User {
     name: String,
     #[serde(serialize_with = "as_str", deserialize_with = "hash_from_str")]
     pin_hash: PasswordHashString,
+    /// A second pin, which authenticates as well, but raises an alert.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "option_as_str",
+        deserialize_with = "option_hash_from_str"
+    )]
+    duress_hash: Option<PasswordHashString>,
+    /// The previous pins, newest first, so they aren't reused.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "vec_as_str",
+        deserialize_with = "vec_hash_from_str"
+    )]
+    history: Vec<PasswordHashString>,
+    /// When the pin was set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+    /// After this the pin isn't accepted anymore.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    disabled: bool,
+    /// Overrides the module's `allowed_services=`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    allowed_services: Option<Vec<String>>,
+    /// Overrides the module's `deny_remote`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deny_remote: Option<bool>,
+    /// Overrides the module's `allowed_ttys=`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    allowed_ttys: Option<Vec<String>>,
+    /// The real password, which is handed to the following modules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sealed_password: Option<SealedPassword>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 fn hash_from_str<'de, D>(deserializer: D) -> Result<PasswordHashString, D::Error>
@@ -25,6 +125,44 @@ where
     PasswordHashString::new(&hash).map_err(|error| Error::custom(error.to_string()))
 }
 
+fn option_hash_from_str<'de, D>(deserializer: D) -> Result<Option<PasswordHashString>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    hash_from_str(deserializer).map(Some)
+}
+
+fn vec_hash_from_str<'de, D>(deserializer: D) -> Result<Vec<PasswordHashString>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|hash| PasswordHashString::new(hash).map_err(|error| Error::custom(error.to_string())))
+        .collect()
+}
+
+fn vec_as_str<T, S>(v: &[T], serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<str>,
+    S: Serializer,
+{
+    serializer.collect_seq(v.iter().map(AsRef::as_ref))
+}
+
+fn option_as_str<T, S>(v: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<str>,
+    S: Serializer,
+{
+    match v {
+        Some(v) => as_str(v, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
 fn as_str<T, S>(v: &T, serializer: S) -> Result<S::Ok, S::Error>
 where
     T: AsRef<str>,
@@ -38,6 +176,15 @@ impl User {
         Self {
             name: name.into(),
             pin_hash: pin,
+            duress_hash: None,
+            history: Vec::new(),
+            created_at: Some(unix_now()),
+            expires_at: None,
+            disabled: false,
+            allowed_services: None,
+            deny_remote: None,
+            allowed_ttys: None,
+            sealed_password: None,
         }
     }
 
@@ -45,6 +192,104 @@ impl User {
         self.pin_hash.password_hash()
     }
 
+    pub fn set_pin_hash(&mut self, pin: PasswordHashString) {
+        self.pin_hash = pin;
+    }
+
+    /// Keeps the previous pin in the history of at most `history_depth` pins.
+    pub fn replace_pin_hash(&mut self, pin: PasswordHashString, history_depth: usize) {
+        let previous = std::mem::replace(&mut self.pin_hash, pin);
+        self.history.insert(0, previous);
+        self.history.truncate(history_depth);
+    }
+
+    /// Takes over the pin and history of the user's `previous` entry,
+    /// like [`Self::replace_pin_hash`].
+    pub fn inherit_history(&mut self, previous: &User, history_depth: usize) {
+        self.history = std::iter::once(&previous.pin_hash)
+            .chain(&previous.history)
+            .take(history_depth)
+            .cloned()
+            .collect();
+    }
+
+    pub fn history(&self) -> impl Iterator<Item = PasswordHash<'_>> {
+        self.history.iter().map(PasswordHashString::password_hash)
+    }
+
+    /// If the pin is the current one or in the history.
+    pub fn is_reused(&self, pin: &[u8], pepper: Option<&Pepper>) -> bool {
+        std::iter::once(self.pin_hash())
+            .chain(self.history())
+            .any(|hash| verify_pin(&hash, pin, pepper).is_ok())
+    }
+
+    pub fn duress_hash(&self) -> Option<PasswordHash<'_>> {
+        self.duress_hash
+            .as_ref()
+            .map(PasswordHashString::password_hash)
+    }
+
+    pub fn set_duress_hash(&mut self, duress_hash: Option<PasswordHashString>) {
+        self.duress_hash = duress_hash;
+    }
+
+    pub fn created_at(&self) -> Option<u64> {
+        self.created_at
+    }
+
+    pub fn set_created_at(&mut self, created_at: Option<u64>) {
+        self.created_at = created_at;
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    pub fn set_expires_at(&mut self, expires_at: Option<u64>) {
+        self.expires_at = expires_at;
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    pub fn set_disabled(&mut self, disabled: bool) {
+        self.disabled = disabled;
+    }
+
+    pub fn allowed_services(&self) -> Option<&[String]> {
+        self.allowed_services.as_deref()
+    }
+
+    pub fn set_allowed_services(&mut self, allowed_services: Option<Vec<String>>) {
+        self.allowed_services = allowed_services;
+    }
+
+    pub fn deny_remote(&self) -> Option<bool> {
+        self.deny_remote
+    }
+
+    pub fn set_deny_remote(&mut self, deny_remote: Option<bool>) {
+        self.deny_remote = deny_remote;
+    }
+
+    pub fn allowed_ttys(&self) -> Option<&[String]> {
+        self.allowed_ttys.as_deref()
+    }
+
+    pub fn set_allowed_ttys(&mut self, allowed_ttys: Option<Vec<String>>) {
+        self.allowed_ttys = allowed_ttys;
+    }
+
+    pub fn sealed_password(&self) -> Option<&SealedPassword> {
+        self.sealed_password.as_ref()
+    }
+
+    pub fn set_sealed_password(&mut self, sealed_password: Option<SealedPassword>) {
+        self.sealed_password = sealed_password;
+    }
+
     pub fn append_to_file(&self, path: &dyn AsRef<Path>) -> error_stack::Result<(), IoSerdeError> {
         let data = Data {
             users: vec![self.clone()],
@@ -90,12 +335,107 @@ impl Data {
     pub fn from_file(path: &dyn AsRef<Path>) -> error_stack::Result<Self, IoSerdeError> {
         let data_string = std::fs::read_to_string(path)
             .change_context(IoSerdeError::Read(path.as_ref().to_path_buf()))?;
-        toml::from_str(&data_string).change_context(IoSerdeError::Deserialize)
+        Self::from_toml(&data_string)
+    }
+
+    /// For files, which have to be read in a special way.
+    pub fn from_toml(data_string: &str) -> error_stack::Result<Self, IoSerdeError> {
+        toml::from_str(data_string).change_context(IoSerdeError::Deserialize)
+    }
+
+    pub fn users(&self) -> &[User] {
+        &self.users
     }
 
     pub fn get_by_name<'a>(&'a self, name: &str) -> Option<&'a User> {
         self.users.iter().rev().find(|user| user.name == name)
     }
+
+    pub fn get_by_name_mut<'a>(&'a mut self, name: &str) -> Option<&'a mut User> {
+        self.users.iter_mut().rev().find(|user| user.name == name)
+    }
+
+    /// Blocks until no other writer holds the lock of the file, e.g. to replace it after changing it.
+    /// Returns `None` for a missing file.
+    pub fn lock_file(
+        path: &dyn AsRef<Path>,
+    ) -> error_stack::Result<Option<FileLock>, IoSerdeError> {
+        match FileLock::acquire(|| File::open(path)) {
+            Ok(lock) => Ok(Some(lock)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => {
+                Err(error).change_context(IoSerdeError::Write(path.as_ref().to_path_buf()))
+            }
+        }
+    }
+
+    pub fn to_toml(&self) -> error_stack::Result<String, IoSerdeError> {
+        toml::to_string(self).change_context(IoSerdeError::Serialize)
+    }
+
+    /// Replaces the whole file atomically.
+    pub fn write_to_file(&self, path: &dyn AsRef<Path>) -> error_stack::Result<(), IoSerdeError> {
+        let data = self.to_toml()?;
+
+        write_atomically(path.as_ref(), data.as_bytes())
+    }
+}
+
+/// Keeps other writers of a file waiting until it's dropped.
+#[must_use]
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Blocks until no other process holds the lock.
+    /// `open` opens the current file, writers replace it by renaming a new file over it,
+    /// so a lock on a replaced file doesn't count.
+    pub fn acquire(mut open: impl FnMut() -> std::io::Result<File>) -> std::io::Result<Self> {
+        loop {
+            let file = open()?;
+            file.lock()?;
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+
+                let locked = file.metadata()?;
+                let current = open()?.metadata()?;
+                if (current.dev(), current.ino()) != (locked.dev(), locked.ino()) {
+                    continue;
+                }
+            }
+            return Ok(Self { _file: file });
+        }
+    }
+}
+
+/// Replaces the file by renaming a fully written sibling over it,
+/// so readers never see a partially written file.
+pub(crate) fn write_atomically(path: &Path, data: &[u8]) -> error_stack::Result<(), IoSerdeError> {
+    let write_error = || IoSerdeError::Write(path.to_path_buf());
+
+    // Unique, so concurrent writers can't write to the same file
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(format!(".{:016x}.tmp", OsRng.next_u64()));
+    let temporary_path = PathBuf::from(temporary_path);
+
+    let mut file_options = File::options();
+    file_options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        file_options.mode(0o600);
+    }
+
+    let mut file = file_options
+        .open(&temporary_path)
+        .change_context_lazy(write_error)?;
+    file.write_all(data).change_context_lazy(write_error)?;
+    file.sync_all().change_context_lazy(write_error)?;
+
+    std::fs::rename(&temporary_path, path).change_context_lazy(write_error)
 }
 
 #[derive(Error, Debug)]
@@ -108,4 +448,40 @@ pub enum IoSerdeError {
     Serialize,
     #[error("Couldn't deserialize file")]
     Deserialize,
+    #[error("Name '{0}' isn't usable as a file name")]
+    InvalidName(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_utc_dates() {
+        assert_eq!(format_unix_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_unix_time(951_868_799), "2000-02-29 23:59:59 UTC");
+        assert_eq!(format_unix_time(1_792_281_600), "2026-10-18 00:00:00 UTC");
+    }
+
+    #[test]
+    fn reject_reused_pins() {
+        let params = argon2::Params::new(8, 1, 1, None).unwrap();
+        let algorithm = HashAlgorithm::default();
+        let hash = |pin: &[u8]| hash_pin(pin, algorithm, params.clone(), None).unwrap();
+
+        let mut user = User::new("user", hash(b"1"));
+        user.replace_pin_hash(hash(b"2"), 2);
+        user.replace_pin_hash(hash(b"3"), 2);
+        user.replace_pin_hash(hash(b"4"), 2);
+        assert_eq!(user.history().count(), 2);
+        assert!(user.is_reused(b"4", None));
+        assert!(user.is_reused(b"2", None));
+        assert!(!user.is_reused(b"1", None));
+
+        let mut rotated_user = User::new("user", hash(b"5"));
+        rotated_user.inherit_history(&user, 2);
+        assert!(rotated_user.is_reused(b"4", None));
+        assert!(rotated_user.is_reused(b"3", None));
+        assert!(!rotated_user.is_reused(b"2", None));
+    }
 }
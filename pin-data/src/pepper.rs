@@ -0,0 +1,180 @@
+use crate::IoSerdeError;
+use argon2::password_hash::PasswordHash;
+use argon2::{KeyId, Params};
+use base64ct::{Base64, Encoding};
+use error_stack::{Report, ResultExt};
+use serde_derive::Deserialize;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Shorter secrets don't add enough to short pins.
+const MIN_SECRET_LENGTH: usize = 16;
+
+/// Argon2's secret for the hashes, kept apart from the database,
+/// so a leaked database alone doesn't allow guessing pins offline.
+/// The first key hashes new pins, the others verify the hashes of older keys.
+/// Each hash records the id of its key.
+pub struct Pepper {
+    keys: Vec<PepperKey>,
+}
+
+struct PepperKey {
+    id: KeyId,
+    secret: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct PepperFile {
+    keys: Vec<PepperFileKey>,
+}
+
+#[derive(Deserialize)]
+struct PepperFileKey {
+    /// At most 8 bytes.
+    id: String,
+    /// Base64
+    secret: String,
+}
+
+impl Pepper {
+    /// The file has to be a regular file owned by root and only accessible by it.
+    pub fn from_file(path: &dyn AsRef<Path>) -> error_stack::Result<Self, IoSerdeError> {
+        let read_error = || IoSerdeError::Read(path.as_ref().to_path_buf());
+
+        // Checked on the opened file, so it can't be swapped in between
+        let mut file = File::open(path).change_context_lazy(read_error)?;
+        let metadata = file.metadata().change_context_lazy(read_error)?;
+        if !metadata.is_file() {
+            return Err(Report::new(read_error()))
+                .attach_printable("The pepper isn't a regular file");
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            if metadata.uid() != 0 || metadata.mode() & 0o077 != 0 {
+                return Err(Report::new(read_error()))
+                    .attach_printable("The pepper has to be only accessible by root");
+            }
+        }
+
+        let mut pepper_string = String::new();
+        file.read_to_string(&mut pepper_string)
+            .change_context_lazy(read_error)?;
+        Self::from_toml(&pepper_string)
+    }
+
+    pub fn from_toml(pepper_string: &str) -> error_stack::Result<Self, IoSerdeError> {
+        let pepper_file: PepperFile =
+            toml::from_str(pepper_string).change_context(IoSerdeError::Deserialize)?;
+
+        let keys = pepper_file
+            .keys
+            .into_iter()
+            .map(|key| {
+                let id = KeyId::new(key.id.as_bytes())
+                    .change_context(IoSerdeError::Deserialize)
+                    .attach_printable_lazy(|| format!("Invalid key id '{}'", key.id))?;
+                let secret = Base64::decode_vec(&key.secret).map_err(|_| {
+                    Report::new(IoSerdeError::Deserialize)
+                        .attach_printable(format!("Invalid secret of key '{}'", key.id))
+                })?;
+                if secret.len() < MIN_SECRET_LENGTH {
+                    return Err(Report::new(IoSerdeError::Deserialize))
+                        .attach_printable(format!("The secret of key '{}' is too short", key.id));
+                }
+                Ok(PepperKey { id, secret })
+            })
+            .collect::<error_stack::Result<Vec<_>, _>>()?;
+
+        if keys.is_empty() {
+            return Err(Report::new(IoSerdeError::Deserialize))
+                .attach_printable("The pepper has no keys");
+        }
+        Ok(Self { keys })
+    }
+
+    /// For new hashes.
+    pub(crate) fn current_key(&self) -> (KeyId, &[u8]) {
+        let key = &self.keys[0];
+        (key.id, &key.secret)
+    }
+
+    pub(crate) fn secret(&self, id: &[u8]) -> Option<&[u8]> {
+        self.keys
+            .iter()
+            .find(|key| key.id.as_bytes() == id)
+            .map(|key| key.secret.as_slice())
+    }
+
+    /// If the hash isn't peppered with the current key, e.g. after a rotation.
+    pub fn is_outdated(&self, hash: &PasswordHash<'_>) -> bool {
+        key_id(hash).as_deref() != Some(self.current_key().0.as_bytes())
+    }
+}
+
+/// Never shows the secrets.
+impl fmt::Debug for Pepper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.keys
+                    .iter()
+                    .map(|key| String::from_utf8_lossy(key.id.as_bytes())),
+            )
+            .finish()
+    }
+}
+
+/// The key id of an Argon2 hash, if it's peppered.
+pub(crate) fn key_id(hash: &PasswordHash<'_>) -> Option<Vec<u8>> {
+    let params = Params::try_from(hash).ok()?;
+    (!params.keyid().is_empty()).then(|| params.keyid().to_vec())
+}
+
+/// If the hash can be verified, i.e. the pepper has its key, if it has one.
+pub fn has_pepper_key(hash: &PasswordHash<'_>, pepper: Option<&Pepper>) -> bool {
+    match key_id(hash) {
+        Some(id) => pepper.is_some_and(|pepper| pepper.secret(&id).is_some()),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify_peppered_pin() {
+        let pepper = |keys: &[(&str, &str)]| {
+            let keys: String = keys
+                .iter()
+                .map(|(id, secret)| format!("[[keys]]\nid = \"{id}\"\nsecret = \"{secret}\"\n"))
+                .collect();
+            Pepper::from_toml(&keys).unwrap()
+        };
+        let old_key = ("1", "MDEyMzQ1Njc4OWFiY2RlZg==");
+        let new_key = ("2", "ZmVkY2JhOTg3NjU0MzIxMA==");
+        let params = argon2::Params::new(8, 1, 1, None).unwrap();
+        let algorithm = crate::HashAlgorithm::default();
+
+        let old_pepper = pepper(&[old_key]);
+        let hash = crate::hash_pin(b"pin", algorithm, params, Some(&old_pepper)).unwrap();
+        let hash = hash.password_hash();
+        crate::verify_pin(&hash, b"pin", Some(&old_pepper)).unwrap();
+        crate::verify_pin(&hash, b"Pin", Some(&old_pepper)).unwrap_err();
+        assert!(!has_pepper_key(&hash, None));
+        crate::verify_pin(&hash, b"pin", None).unwrap_err();
+
+        let rotated_pepper = pepper(&[new_key, old_key]);
+        crate::verify_pin(&hash, b"pin", Some(&rotated_pepper)).unwrap();
+        assert!(rotated_pepper.is_outdated(&hash));
+        assert!(!old_pepper.is_outdated(&hash));
+        assert!(!has_pepper_key(&hash, Some(&pepper(&[new_key]))));
+
+        let _ = Pepper::from_toml("keys = []").unwrap_err();
+        let _ = Pepper::from_toml("[[keys]]\nid = \"1\"\nsecret = \"c2hvcnQ=\"").unwrap_err();
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyError {
+    #[error("The pin has invalid characters")]
+    Charset,
+    #[error("The pin is too short")]
+    TooShort,
+    #[error("The pin is too long")]
+    TooLong,
+    #[error("Unknown charset, expected `digits`, `alnum` or `any`")]
+    UnknownCharset,
+}
+
+/// The characters a pin may have.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Charset {
+    Digits,
+    /// ASCII letters and digits
+    Alnum,
+    #[default]
+    Any,
+}
+
+impl Charset {
+    fn allows(&self, character: char) -> bool {
+        match self {
+            Self::Digits => character.is_ascii_digit(),
+            Self::Alnum => character.is_ascii_alphanumeric(),
+            Self::Any => true,
+        }
+    }
+}
+
+impl FromStr for Charset {
+    type Err = PolicyError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "digits" => Ok(Self::Digits),
+            "alnum" => Ok(Self::Alnum),
+            "any" => Ok(Self::Any),
+            _ => Err(PolicyError::UnknownCharset),
+        }
+    }
+}
+
+impl fmt::Display for Charset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Digits => "digits",
+            Self::Alnum => "alnum",
+            Self::Any => "any",
+        })
+    }
+}
+
+/// What pins have to look like.
+/// The lengths are in characters.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PinPolicy {
+    pub charset: Charset,
+    pub min_len: Option<usize>,
+    pub max_len: Option<usize>,
+}
+
+impl PinPolicy {
+    /// Cheap, so it can reject pins before hashing.
+    pub fn check(&self, pin: &[u8]) -> Result<(), PolicyError> {
+        let length = match std::str::from_utf8(pin) {
+            Ok(pin) => {
+                if !pin.chars().all(|character| self.charset.allows(character)) {
+                    return Err(PolicyError::Charset);
+                }
+                pin.chars().count()
+            }
+            Err(_) if self.charset == Charset::Any => pin.len(),
+            Err(_) => return Err(PolicyError::Charset),
+        };
+
+        if self.min_len.is_some_and(|min_len| length < min_len) {
+            return Err(PolicyError::TooShort);
+        }
+        if self.max_len.is_some_and(|max_len| length > max_len) {
+            return Err(PolicyError::TooLong);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check_pin_policy() {
+        let six_digits = PinPolicy {
+            charset: Charset::Digits,
+            min_len: Some(6),
+            max_len: Some(6),
+        };
+        six_digits.check(b"123456").unwrap();
+        assert_eq!(six_digits.check(b"12345"), Err(PolicyError::TooShort));
+        assert_eq!(six_digits.check(b"1234567"), Err(PolicyError::TooLong));
+        assert_eq!(six_digits.check(b"12345a"), Err(PolicyError::Charset));
+
+        let alnum = PinPolicy {
+            charset: Charset::Alnum,
+            ..Default::default()
+        };
+        alnum.check(b"abc123").unwrap();
+        alnum.check("äbc".as_bytes()).unwrap_err();
+
+        let any = PinPolicy {
+            max_len: Some(3),
+            ..Default::default()
+        };
+        any.check("äöü".as_bytes()).unwrap();
+        any.check(&[0xff, 0xfe]).unwrap();
+    }
+}
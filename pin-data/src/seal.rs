@@ -0,0 +1,158 @@
+use crate::hash::with_key_id;
+use crate::pepper::{self, Pepper};
+use crate::{as_str, hash_from_str};
+use argon2::password_hash::{
+    rand_core::OsRng, rand_core::RngCore, PasswordHash, PasswordHashString, PasswordHasher,
+    SaltString,
+};
+use argon2::{Argon2, Params, Version};
+use base64ct::{Base64Unpadded, Encoding};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+
+const NONCE_LENGTH: usize = 12;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SealError {
+    #[error("Couldn't derive the key from the pin")]
+    DeriveKey,
+    #[error("Couldn't encrypt the password")]
+    Encrypt,
+    #[error("Couldn't decrypt the password")]
+    Decrypt,
+    #[error("The pepper doesn't have the key of the sealed password")]
+    MissingPepperKey,
+}
+
+/// A password encrypted with a key derived from the pin.
+/// Like the pin hashes, the key is derived with the current pepper key, if there is a pepper.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SealedPassword {
+    /// The Argon2id parameters and salt of the key, without the key itself.
+    #[serde(serialize_with = "as_str", deserialize_with = "hash_from_str")]
+    key: PasswordHashString,
+    /// Base64 of the nonce followed by the ChaCha20-Poly1305 ciphertext.
+    ciphertext: String,
+}
+
+impl SealedPassword {
+    pub fn seal(
+        password: &[u8],
+        pin: &[u8],
+        argon2_params: Params,
+        pepper: Option<&Pepper>,
+    ) -> Result<Self, SealError> {
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2 = match pepper.map(Pepper::current_key) {
+            Some((key_id, secret)) => Argon2::new_with_secret(
+                secret,
+                argon2::Algorithm::Argon2id,
+                Version::default(),
+                with_key_id(&argon2_params, key_id).map_err(|_| SealError::DeriveKey)?,
+            )
+            .map_err(|_| SealError::DeriveKey)?,
+            None => Argon2::new(
+                argon2::Algorithm::Argon2id,
+                Version::default(),
+                argon2_params,
+            ),
+        };
+        let key_hash = argon2
+            .hash_password(pin, &salt)
+            .map_err(|_| SealError::DeriveKey)?;
+        let cipher = cipher(&key_hash)?;
+
+        let mut nonce = [0; NONCE_LENGTH];
+        OsRng.fill_bytes(&mut nonce);
+        let mut ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), password)
+            .map_err(|_| SealError::Encrypt)?;
+        ciphertext.splice(0..0, nonce);
+
+        let mut key = key_hash;
+        key.hash = None;
+        Ok(Self {
+            key: key.serialize(),
+            ciphertext: Base64Unpadded::encode_string(&ciphertext),
+        })
+    }
+
+    pub fn unseal(&self, pin: &[u8], pepper: Option<&Pepper>) -> Result<Vec<u8>, SealError> {
+        let key = self.key();
+        let params = Params::try_from(&key).map_err(|_| SealError::DeriveKey)?;
+        let salt = key.salt.ok_or(SealError::DeriveKey)?;
+        let argon2 = match pepper::key_id(&key) {
+            Some(key_id) => {
+                let secret = pepper
+                    .and_then(|pepper| pepper.secret(&key_id))
+                    .ok_or(SealError::MissingPepperKey)?;
+                Argon2::new_with_secret(
+                    secret,
+                    Default::default(),
+                    Version::default(),
+                    Params::default(),
+                )
+                .map_err(|_| SealError::DeriveKey)?
+            }
+            None => Argon2::default(),
+        };
+        let key_hash = argon2
+            .hash_password_customized(pin, Some(key.algorithm), key.version, params, salt)
+            .map_err(|_| SealError::DeriveKey)?;
+        let cipher = cipher(&key_hash)?;
+
+        let ciphertext =
+            Base64Unpadded::decode_vec(&self.ciphertext).map_err(|_| SealError::Decrypt)?;
+        if ciphertext.len() < NONCE_LENGTH {
+            return Err(SealError::Decrypt);
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(NONCE_LENGTH);
+        cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| SealError::Decrypt)
+    }
+
+    /// Has to be checked like a pin hash before unsealing.
+    pub fn key(&self) -> PasswordHash<'_> {
+        self.key.password_hash()
+    }
+}
+
+fn cipher(key_hash: &PasswordHash<'_>) -> Result<ChaCha20Poly1305, SealError> {
+    let key = key_hash.hash.ok_or(SealError::DeriveKey)?;
+    if key.len() != 32 {
+        return Err(SealError::DeriveKey);
+    }
+    Ok(ChaCha20Poly1305::new(Key::from_slice(key.as_bytes())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seal_with_pepper() {
+        let pepper =
+            Pepper::from_toml("[[keys]]\nid = \"1\"\nsecret = \"MDEyMzQ1Njc4OWFiY2RlZg==\"")
+                .unwrap();
+        let params = Params::new(8, 1, 1, None).unwrap();
+
+        let sealed_password =
+            SealedPassword::seal(b"password", b"pin", params, Some(&pepper)).unwrap();
+        assert!(!pepper.is_outdated(&sealed_password.key()));
+        assert_eq!(
+            sealed_password.unseal(b"pin", Some(&pepper)).unwrap(),
+            b"password"
+        );
+        assert_eq!(
+            sealed_password.unseal(b"pin", None),
+            Err(SealError::MissingPepperKey)
+        );
+        assert_eq!(
+            sealed_password.unseal(b"Pin", Some(&pepper)),
+            Err(SealError::Decrypt)
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use crate::{write_atomically, FileLock, IoSerdeError};
+use error_stack::ResultExt;
+use path_ratchet::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::PathBuf;
+
+/// The mutable part of a user's pin data.
+/// It is kept apart from the database, so the database can stay read-only.
+#[derive(Default, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UserState {
+    /// Failed attempts since the last successful authentication.
+    #[serde(default)]
+    pub failed_attempts: u32,
+    /// Unix timestamp of the first failure counted in `failed_attempts`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_failure: Option<u64>,
+    /// Unix timestamp of the latest failure counted in `failed_attempts`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_failure: Option<u64>,
+    /// Unix timestamp of the latest successful authentication with the pin.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<u64>,
+    /// Unix timestamp of the latest failed authentication with the pin.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_failed: Option<u64>,
+    /// Unlike `failed_attempts`, this isn't reset by the lockout interval.
+    #[serde(default)]
+    pub failed_since_last_use: u32,
+    /// Unix timestamp of the latest login with the full password.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_password_login: Option<u64>,
+}
+
+/// A directory with one state file per user.
+#[derive(Clone, Debug)]
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn user_file(&self, name: &str) -> error_stack::Result<PathBuf, IoSerdeError> {
+        let mut user_file = self.dir.clone();
+        let file_name = SingleComponentPath::new(name)
+            .ok_or_else(|| IoSerdeError::InvalidName(name.to_string()))?;
+        user_file.push_component(file_name);
+        Ok(user_file)
+    }
+
+    /// Missing state files are treated as an empty state.
+    pub fn load(&self, name: &str) -> error_stack::Result<UserState, IoSerdeError> {
+        let path = self.user_file(name)?;
+
+        let state_string = match std::fs::read_to_string(&path) {
+            Ok(state_string) => state_string,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(UserState::default())
+            }
+            Err(error) => return Err(error).change_context(IoSerdeError::Read(path)),
+        };
+        toml::from_str(&state_string).change_context(IoSerdeError::Deserialize)
+    }
+
+    /// Blocks until no other process holds the lock of the user's state,
+    /// so e.g. parallel failed attempts can't overwrite each other.
+    /// The state file is created empty, if it doesn't exist.
+    pub fn lock(&self, name: &str) -> error_stack::Result<FileLock, IoSerdeError> {
+        let path = self.user_file(name)?;
+
+        let mut file_options = File::options();
+        file_options.read(true).write(true).create(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            file_options.mode(0o600);
+        }
+        FileLock::acquire(|| file_options.open(&path))
+            .change_context_lazy(|| IoSerdeError::Write(path.clone()))
+    }
+
+    pub fn store(&self, name: &str, state: &UserState) -> error_stack::Result<(), IoSerdeError> {
+        let path = self.user_file(name)?;
+        let state = toml::to_string(state).change_context(IoSerdeError::Serialize)?;
+
+        write_atomically(&path, state.as_bytes())
+    }
+}
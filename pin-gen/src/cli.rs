@@ -1,6 +1,7 @@
 use crate::{Error, Result};
 use clap::{Parser, ValueHint};
 use error_stack::ResultExt;
+use pin_data::{Charset, HashAlgorithm, PinPolicy};
 use std::path::PathBuf;
 
 #[derive(Parser, Debug, Clone)]
@@ -15,10 +16,30 @@ pub struct CliArgs {
     pub database_filepath: PathBuf,
     #[clap(env = "SUDO_USER", value_hint(ValueHint::Username))]
     pub username: Option<String>,
+    /// Show the last use and failed attempts of the user
+    /// from the state directory of `pam-pin` instead of creating a pin.
+    #[clap(long, value_name = "STATE_DIR", value_hint(ValueHint::DirPath))]
+    pub show: Option<PathBuf>,
+    /// Disable all pins on the host by creating the disable file of `pam-pin`,
+    /// e.g. during an incident.
+    #[clap(long, conflicts_with = "enable")]
+    pub disable: bool,
+    /// Enable the pins again by removing the disable file.
+    #[clap(long)]
+    pub enable: bool,
+    /// The disable file for `--disable` and `--enable`.
+    /// The default is next to the database, as for `pam-pin`.
+    #[clap(long, value_hint(ValueHint::FilePath))]
+    pub disable_file: Option<PathBuf>,
     /// Use this flag to try different parameters.
     /// It disables the need for a username and pin.
     #[clap(short, long)]
     pub benchmark: bool,
+    /// The password hashing function.
+    /// `argon2d`, `argon2i` and `argon2id` are always available,
+    /// `scrypt` and `pbkdf2-sha256` need the corresponding features.
+    #[clap(short, long, default_value_t)]
+    pub algorithm: HashAlgorithm,
     /// For Argon2.
     /// Try to use in the range of 65536 KiB and available memory / 2.
     /// The unit is KiB.
@@ -32,15 +53,60 @@ pub struct CliArgs {
     /// Use the number of physical threads.
     #[clap(short, long)]
     pub parallelism: Option<u32>,
+    /// A root-only file with the secret keys for Argon2, like `pepper=` of `pam-pin`.
+    /// The first key is used.
+    #[clap(long, value_hint(ValueHint::FilePath))]
+    pub pepper: Option<PathBuf>,
+    /// Also ask for the user's real password and store it encrypted with the pin.
+    /// `pam-pin` hands it to the following modules, e.g. to unlock a keyring.
+    #[clap(short, long)]
+    pub seal_password: bool,
+    /// The characters the pin may have: `digits`, `alnum` or `any`,
+    /// like `pin_charset=` of `pam-pin`.
+    #[clap(long, default_value_t)]
+    pub pin_charset: Charset,
+    /// The minimum number of characters of the pin.
+    #[clap(long)]
+    pub min_len: Option<usize>,
+    /// The maximum number of characters of the pin.
+    #[clap(long)]
+    pub max_len: Option<usize>,
+    /// Keep the hashes of the last pins of the user and reject them as the new pin,
+    /// like `remember=` of `pam-pin`.
+    #[clap(short, long, default_value_t = 0)]
+    pub remember: usize,
+    /// Also ask for a duress pin.
+    /// It authenticates like the pin, but `pam-pin` raises an alert.
+    #[clap(short, long)]
+    pub duress: bool,
 }
 
 impl CliArgs {
     pub fn validate(&self) -> Result<()> {
-        (self.benchmark || self.username.is_some())
+        (self.benchmark || self.is_switch() || self.username.is_some())
             .then_some(())
             .ok_or(Error::NoUsername.into())
     }
 
+    /// `--disable` or `--enable`
+    pub fn is_switch(&self) -> bool {
+        self.disable || self.enable
+    }
+
+    pub fn disable_file(&self) -> PathBuf {
+        self.disable_file
+            .clone()
+            .unwrap_or_else(|| pin_data::default_disable_file(&self.database_filepath))
+    }
+
+    pub fn pin_policy(&self) -> PinPolicy {
+        PinPolicy {
+            charset: self.pin_charset,
+            min_len: self.min_len,
+            max_len: self.max_len,
+        }
+    }
+
     pub fn argon2_params(&self) -> Result<argon2::Params> {
         let mut argon2_params = argon2::ParamsBuilder::new();
 
@@ -1,10 +1,13 @@
 mod cli;
 
-use argon2::{password_hash, Algorithm, Argon2, Params, Version};
+use argon2::{password_hash, Params};
 use clap::Parser;
 use error_stack::ResultExt;
-use password_hash::{rand_core::OsRng, PasswordHashString, PasswordHasher, SaltString};
-use pin_data::User;
+use password_hash::PasswordHashString;
+use pin_data::state::StateStore;
+use pin_data::{HashAlgorithm, Pepper, SealedPassword, User};
+use std::fs::File;
+use std::path::Path;
 use std::time::Instant;
 use sysexits::ExitCode;
 
@@ -20,8 +23,24 @@ pub enum Error {
     ReadPassword,
     #[error("Couldn't hash password")]
     HashPassword,
+    #[error("Couldn't seal password")]
+    SealPassword,
+    #[error("The duress pin has to differ from the pin")]
+    SameDuressPin,
+    #[error("The pin was used before")]
+    PinReused,
+    #[error("Couldn't read the database")]
+    ReadDatabase,
+    #[error("Couldn't load the pepper")]
+    LoadPepper,
+    #[error("The pin doesn't meet the requirements")]
+    PinPolicy,
     #[error("Couldn't write to database")]
     WriteDatabase,
+    #[error("Couldn't read the state")]
+    ReadState,
+    #[error("Couldn't switch the pins")]
+    Switch,
 }
 
 type Result<T> = error_stack::Result<T, Error>;
@@ -40,23 +59,100 @@ fn try_main() -> Result<()> {
 
     args.validate().attach(ExitCode::Usage)?;
 
+    if let (Some(state_dir), Some(username)) = (&args.show, &args.username) {
+        return show_state(state_dir, username);
+    }
+
+    if args.is_switch() {
+        return switch_pins(&args.disable_file(), args.enable);
+    }
+
     let argon2_params = args.argon2_params().attach(ExitCode::Usage)?;
+    let pepper = args
+        .pepper
+        .as_ref()
+        .map(|pepper| Pepper::from_file(pepper))
+        .transpose()
+        .change_context(Error::LoadPepper)?;
 
     let pin = if args.benchmark {
         "Pin".to_string()
     } else {
-        rpassword::prompt_password("Pin: ").change_context(Error::ReadPassword)?
+        let pin = rpassword::prompt_password("Pin: ").change_context(Error::ReadPassword)?;
+        check_pin_policy(&args, &pin)?;
+        pin
+    };
+
+    let sealed_password = if args.seal_password && !args.benchmark {
+        let password =
+            rpassword::prompt_password("Password: ").change_context(Error::ReadPassword)?;
+        Some(
+            SealedPassword::seal(
+                password.as_bytes(),
+                pin.as_bytes(),
+                argon2_params.clone(),
+                pepper.as_ref(),
+            )
+            .change_context(Error::SealPassword)?,
+        )
+    } else {
+        None
+    };
+
+    let duress_hash = if args.duress && !args.benchmark {
+        let duress_pin =
+            rpassword::prompt_password("Duress pin: ").change_context(Error::ReadPassword)?;
+        if duress_pin == pin {
+            return Err(Error::SameDuressPin).attach(ExitCode::Usage);
+        }
+        // Otherwise `pam-pin` would reject it before verifying
+        check_pin_policy(&args, &duress_pin)?;
+        Some(hash_pin(
+            duress_pin,
+            args.algorithm,
+            argon2_params.clone(),
+            pepper.as_ref(),
+        )?)
+    } else {
+        None
     };
 
+    // Held until the entry is appended, so e.g. a rehash of `pam-pin` can't replace the file
+    // in between and the history is the one of the current entry
+    let _database_lock = if args.benchmark {
+        None
+    } else {
+        pin_data::Data::lock_file(&args.database_filepath).change_context(Error::WriteDatabase)?
+    };
+    let previous_user = if args.remember > 0 && !args.benchmark {
+        previous_user(
+            &args.database_filepath,
+            args.username.as_deref().unwrap_or_default(),
+        )?
+    } else {
+        None
+    };
+    if previous_user
+        .as_ref()
+        .is_some_and(|previous_user| previous_user.is_reused(pin.as_bytes(), pepper.as_ref()))
+    {
+        return Err(Error::PinReused).attach(ExitCode::DataErr);
+    }
+
     let hashing_starting_time = Instant::now();
-    let hash = hash_pin(pin, argon2_params)?;
+    let hash = hash_pin(pin, args.algorithm, argon2_params, pepper.as_ref())?;
     eprintln!(
         "Needed {}ms for hashing",
         hashing_starting_time.elapsed().as_millis()
     );
 
     if !args.benchmark {
-        let user = User::new(args.username.unwrap(), hash);
+        let mut user = User::new(args.username.unwrap(), hash);
+        user.set_sealed_password(sealed_password);
+        user.set_duress_hash(duress_hash);
+        if let Some(previous_user) = &previous_user {
+            user.inherit_history(previous_user, args.remember);
+        }
 
         user.append_to_file(&args.database_filepath)
             .change_context(Error::WriteDatabase)?;
@@ -64,6 +160,58 @@ fn try_main() -> Result<()> {
     Ok(())
 }
 
+fn check_pin_policy(args: &cli::CliArgs, pin: &str) -> Result<()> {
+    args.pin_policy()
+        .check(pin.as_bytes())
+        .change_context(Error::PinPolicy)
+        .attach(ExitCode::DataErr)
+}
+
+/// The current entry of the user, whose pin is replaced.
+fn previous_user(database_filepath: &Path, username: &str) -> Result<Option<User>> {
+    if !database_filepath.exists() {
+        return Ok(None);
+    }
+    let data = pin_data::Data::from_file(&database_filepath).change_context(Error::ReadDatabase)?;
+    Ok(data.get_by_name(username).cloned())
+}
+
+fn show_state(state_dir: &Path, username: &str) -> Result<()> {
+    let state = StateStore::new(state_dir)
+        .load(username)
+        .change_context(Error::ReadState)?;
+    let format_time =
+        |time: Option<u64>| time.map_or_else(|| "never".to_string(), pin_data::format_unix_time);
+
+    println!("Last use: {}", format_time(state.last_used));
+    println!("Last failure: {}", format_time(state.last_failed));
+    println!(
+        "Failed attempts since the last use: {}",
+        state.failed_since_last_use
+    );
+    Ok(())
+}
+
+/// Creates or removes the disable file.
+fn switch_pins(disable_file: &Path, is_enable: bool) -> Result<()> {
+    if is_enable {
+        match std::fs::remove_file(disable_file) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                return Err(error).change_context(Error::Switch);
+            }
+            _ => (),
+        }
+        eprintln!("Enabled the pins");
+    } else {
+        File::create(disable_file).change_context(Error::Switch)?;
+        eprintln!(
+            "Disabled the pins until {} is removed",
+            disable_file.display()
+        );
+    }
+    Ok(())
+}
+
 fn main() -> std::process::ExitCode {
     if let Err(report) = try_main() {
         eprintln!("Error: {:?}", report);
@@ -88,7 +236,26 @@ fn setup_sandbox(args: &cli::CliArgs) -> Result<()> {
         .change_context(Error::Sandbox)
         .attach_printable("Initialization failed")?;
 
-    if !args.benchmark {
+    if args.is_switch() {
+        birdcage
+            .add_exception(birdcage::Exception::Write(parent_dir(
+                &args.disable_file(),
+            )?))
+            .change_context(Error::Sandbox)
+            .attach_printable("Couldn't set the directory of the disable file as writeable")?;
+    } else if let Some(state_dir) = &args.show {
+        birdcage
+            .add_exception(birdcage::Exception::Read(state_dir.clone()))
+            .change_context(Error::Sandbox)
+            .attach_printable("Couldn't set the state directory as readable")?;
+    } else if !args.benchmark {
+        if let Some(pepper) = &args.pepper {
+            birdcage
+                .add_exception(birdcage::Exception::Read(pepper.clone()))
+                .change_context(Error::Sandbox)
+                .attach_printable("Couldn't set the pepper as readable")?;
+        }
+
         // prompt_password
         const TTY_PATH: &str = "/dev/tty";
         birdcage
@@ -99,17 +266,10 @@ fn setup_sandbox(args: &cli::CliArgs) -> Result<()> {
             .change_context(Error::Sandbox)?;
 
         // Use the parent as the database file could be nonexistent
-        let mut database_parent = args
-            .database_filepath
-            .parent()
-            .ok_or(Error::Sandbox)
-            .attach_printable("Couldn't get the parent directory of the database")?
-            .to_path_buf();
-        if database_parent.as_os_str().is_empty() {
-            database_parent = ".".into();
-        }
         birdcage
-            .add_exception(birdcage::Exception::Write(database_parent))
+            .add_exception(birdcage::Exception::Write(parent_dir(
+                &args.database_filepath,
+            )?))
             .change_context(Error::Sandbox)
             .attach_printable("Couldn't set the database file as writeable")?;
     }
@@ -120,13 +280,25 @@ fn setup_sandbox(args: &cli::CliArgs) -> Result<()> {
         .attach_printable("Couldn't activate sandbox")
 }
 
-fn hash_pin(pin: String, argon2_params: Params) -> Result<PasswordHashString> {
-    let argon2 = Argon2::new(Algorithm::Argon2d, Version::default(), argon2_params);
-
-    let salt = SaltString::generate(&mut OsRng);
+#[cfg(feature = "sandbox")]
+fn parent_dir(path: &Path) -> Result<std::path::PathBuf> {
+    let mut parent = path
+        .parent()
+        .ok_or(Error::Sandbox)
+        .attach_printable("Couldn't get the parent directory")?
+        .to_path_buf();
+    if parent.as_os_str().is_empty() {
+        parent = ".".into();
+    }
+    Ok(parent)
+}
 
-    argon2
-        .hash_password(pin.as_bytes(), &salt)
-        .map(|hash| hash.serialize())
+fn hash_pin(
+    pin: String,
+    algorithm: HashAlgorithm,
+    argon2_params: Params,
+    pepper: Option<&Pepper>,
+) -> Result<PasswordHashString> {
+    pin_data::hash_pin(pin.as_bytes(), algorithm, argon2_params, pepper)
         .change_context(Error::HashPassword)
 }